@@ -2,6 +2,9 @@
 use std;
 use getopts;
 
+use warnings::WarningOptions;
+use diagnostic::DiagnosticFormat;
+
 pub fn make_options() -> getopts::Options {
     let mut opts = getopts::Options::new();
 
@@ -26,7 +29,22 @@ pub fn make_options() -> getopts::Options {
     opts.optflag(
         "q",
         "quiet",
-        "run in quiet mode",
+        "run in quiet mode, without printing any warnings",
+    );
+
+    opts.opt(
+        "W",
+        "",
+        "enable a warning, or with no- disable it, or with error= make it an error",
+        "WARNING",
+        getopts::HasArg::Yes,
+        getopts::Occur::Multi,
+    );
+
+    opts.optflag(
+        "w",
+        "",
+        "inhibit all warnings",
     );
 
     opts.opt(
@@ -56,6 +74,21 @@ pub fn make_options() -> getopts::Options {
         getopts::Occur::Multi,
     );
 
+    opts.opt(
+        "",
+        "isystem",
+        "specify system include directories, searched after the -I ones",
+        "INCLUDE_DIR",
+        getopts::HasArg::Yes,
+        getopts::Occur::Multi,
+    );
+
+    opts.optflag(
+        "P",
+        "",
+        "do not print linemarkers",
+    );
+
     opts.opt(
         "D",
         "define",
@@ -65,10 +98,113 @@ pub fn make_options() -> getopts::Options {
         getopts::Occur::Multi,
     );
 
+    opts.opt(
+        "",
+        "std",
+        "select the language standard, such as c99, c17, c23 or gnu17",
+        "STANDARD",
+        getopts::HasArg::Yes,
+        getopts::Occur::Optional,
+    );
+
+    opts.opt(
+        "x",
+        "",
+        "select the input language, c or objective-c",
+        "LANGUAGE",
+        getopts::HasArg::Yes,
+        getopts::Occur::Optional,
+    );
+
+    opts.opt(
+        "",
+        "diagnostics-format",
+        "print errors and warnings as text, json or sarif",
+        "FORMAT",
+        getopts::HasArg::Yes,
+        getopts::Occur::Optional,
+    );
+
+    opts.opt(
+        "f",
+        "",
+        "set a preprocessor limit, such as max-include-depth=N or max-errors=N",
+        "OPTION=VALUE",
+        getopts::HasArg::Yes,
+        getopts::Occur::Multi,
+    );
+
     opts
 }
 
 
+/// Parses a language standard name as accepted by gcc's `-std`, returning the
+/// standard and whether GNU extensions are enabled.
+fn parse_standard(name: &str) -> Result<(Standard, bool), String> {
+    let gnu = name.starts_with("gnu");
+    let version = if gnu { &name[3..] } else if name.starts_with('c') { &name[1..] } else { name };
+
+    let std = match version {
+        "89" | "90" | "ansi" | "iso9899:1990" => Standard::C89,
+        "99" | "9x" | "iso9899:1999" => Standard::C99,
+        "11" | "1x" | "iso9899:2011" => Standard::C11,
+        "17" | "18" | "iso9899:2017" | "iso9899:2018" => Standard::C17,
+        "23" | "2x" | "iso9899:2024" => Standard::C23,
+        _ => return Err(format!("Unrecognized language standard {:?}", name)),
+    };
+
+    Ok((std, gnu))
+}
+
+
+/// Rewrites gcc's single dash `-std=` and `-isystem` options into the
+/// `--std=` and `--isystem` that getopts understands.
+fn normalize_option(arg: String) -> String {
+    if arg.starts_with("-std=") {
+        format!("-{}", arg)
+    }
+    else if arg == "-isystem" {
+        String::from("--isystem")
+    }
+    else if arg.starts_with("-isystem") {
+        format!("--isystem={}", &arg["-isystem".len()..])
+    }
+    else {
+        arg
+    }
+}
+
+
+fn parse_language(name: &str) -> Result<Language, String> {
+    match name {
+        "c" | "c-header" => Ok(Language::C),
+        "objective-c" | "objective-c-header" => Ok(Language::ObjectiveC),
+        _ => Err(format!("Unrecognized language {:?}, expected c or objective-c", name)),
+    }
+}
+
+
+fn parse_flag_option(config: &mut Config, flag: &str) -> Result<(), String> {
+    let mut parts = flag.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let value = parts.next();
+
+    match (name, value) {
+        ("max-include-depth", Some(value)) => {
+            config.max_include_depth = value.parse().map_err(|_| format!("Invalid include depth {:?}", value))?;
+        }
+        ("max-errors", Some(value)) => {
+            config.max_errors = value.parse().map_err(|_| format!("Invalid maximum number of errors {:?}", value))?;
+        }
+        _ => {
+            return Err(format!("Unrecognized option -f{}", flag));
+        }
+    }
+
+    Ok(())
+}
+
+
 pub fn make_config(args: Vec<String>) -> Result<Config, String> {
     //let args: Vec<String> = std::env::args().collect();
     let mut opts = make_options();
@@ -76,7 +212,8 @@ pub fn make_config(args: Vec<String>) -> Result<Config, String> {
 
     config.program_name = args[0].clone();
 
-    let matches = match opts.parse(&args[1..]) {
+    let args: Vec<String> = args[1..].iter().cloned().map(normalize_option).collect();
+    let matches = match opts.parse(&args) {
         Ok(m) => m,
         Err(e) => {
             return Err(e.to_string());
@@ -91,20 +228,80 @@ pub fn make_config(args: Vec<String>) -> Result<Config, String> {
     }
 
     config.include_dirs = matches.opt_strs("include-path").into_iter().map(|s| std::path::PathBuf::from(s)).collect();
+    config.system_include_dirs = matches.opt_strs("isystem").into_iter().map(|s| std::path::PathBuf::from(s)).collect();
     config.macro_defs = matches.opt_strs("define");
+    config.line_markers = !matches.opt_present("P");
+
+    if let Some(name) = matches.opt_str("std") {
+        let (std, gnu) = parse_standard(&name)?;
+        config.std = std;
+        config.gnu_extensions = gnu;
+    }
+
+    if let Some(name) = matches.opt_str("x") {
+        config.language = parse_language(&name)?;
+    }
+
+    for flag in matches.opt_strs("f") {
+        parse_flag_option(&mut config, &flag)?;
+    }
+
+    for warning in matches.opt_strs("W") {
+        config.warnings.apply(&warning)?;
+    }
+    config.warnings.inhibit = matches.opt_present("w") || matches.opt_present("quiet");
+
+    if let Some(name) = matches.opt_str("diagnostics-format") {
+        config.diagnostics_format = DiagnosticFormat::from_name(&name)
+            .ok_or_else(|| format!("Unrecognized diagnostics format {:?}, expected text, json or sarif", name))?;
+    }
+
     config.input_files = matches.free;
 
     Ok(config)
 }
 
 
+/// The revisions of the C standard that the preprocessor can follow.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Standard {
+    C89,
+    C99,
+    C11,
+    C17,
+    C23,
+}
+
+
+/// The languages whose sources the preprocessor can read.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Language {
+    C,
+    /// Objective-C, which adds the `#import` directive.
+    ObjectiveC,
+}
+
+
 pub struct Config {
     pub opts: getopts::Options,
     pub program_name: String,
     pub help_flag: bool,
     pub include_dirs: Vec<std::path::PathBuf>,
+    /// Directories given with `-isystem`, followed by the default ones, whose
+    /// headers are system headers.
+    pub system_include_dirs: Vec<std::path::PathBuf>,
     pub macro_defs: Vec<String>,
     pub input_files: Vec<String>,
+    pub max_include_depth: usize,
+    pub std: Standard,
+    pub gnu_extensions: bool,
+    pub language: Language,
+    pub warnings: WarningOptions,
+    /// Stop after this many errors, or never when it is 0.
+    pub max_errors: usize,
+    pub diagnostics_format: DiagnosticFormat,
+    /// Whether to print linemarkers where the output enters and leaves files.
+    pub line_markers: bool,
 }
 
 
@@ -115,16 +312,38 @@ impl Config {
             program_name: String::new(),
             help_flag: false,
             include_dirs: Vec::new(),
+            system_include_dirs: Vec::new(),
             macro_defs: Vec::new(),
             input_files: Vec::new(),
+            max_include_depth: 200,
+            std: Standard::C17,
+            gnu_extensions: true,
+            language: Language::C,
+            warnings: WarningOptions::new(),
+            max_errors: 0,
+            diagnostics_format: DiagnosticFormat::Text,
+            line_markers: true,
         }
     }
 
+    /// The name of the selected language standard, as it is spelled for `--std`.
+    pub fn std_name(&self) -> String {
+        let version = match self.std {
+            Standard::C89 => "89",
+            Standard::C99 => "99",
+            Standard::C11 => "11",
+            Standard::C17 => "17",
+            Standard::C23 => "23",
+        };
+
+        format!("{}{}", if self.gnu_extensions { "gnu" } else { "c" }, version)
+    }
+
     pub fn add_default_include_dirs(&mut self) {
         let default_include_dirs = ["/usr/include", "/usr/local/include"];
 
         for dir in default_include_dirs.iter() {
-            self.include_dirs.push(std::path::PathBuf::from(dir));
+            self.system_include_dirs.push(std::path::PathBuf::from(dir));
         }
     }
 }
@@ -135,3 +354,33 @@ impl Default for Config {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::{make_config, Standard};
+
+    fn config_from(args: &[&str]) -> super::Config {
+        let mut all_args = vec![String::from("rcpp")];
+        all_args.extend(args.iter().map(|arg| arg.to_string()));
+        make_config(all_args).unwrap()
+    }
+
+    #[test]
+    fn std_accepts_both_spellings() {
+        for &option in ["-std=c11", "--std=c11"].iter() {
+            let config = config_from(&[option, "main.c"]);
+            assert!(config.std == Standard::C11);
+            assert!(!config.gnu_extensions);
+            assert_eq!(config.input_files, vec!["main.c"]);
+        }
+
+        let config = config_from(&["-std=gnu99"]);
+        assert!(config.std == Standard::C99);
+        assert!(config.gnu_extensions);
+    }
+
+    #[test]
+    fn std_rejects_unknown_standards() {
+        assert!(make_config(vec![String::from("rcpp"), String::from("-std=c42")]).is_err());
+    }
+}
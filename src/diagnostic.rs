@@ -0,0 +1,506 @@
+
+use std::fmt;
+use std::cell::{Cell, RefCell};
+
+use source::{FileId, SourceLocation, SourceManager};
+use warnings::{Level, Warning, WarningOptions};
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Note,
+    Warning,
+    Error,
+    Fatal,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+            Severity::Fatal => "fatal error",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+
+/// How diagnostics are written out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DiagnosticFormat {
+    /// gcc style text, printed as each diagnostic is reported.
+    Text,
+    /// A JSON array in the layout of gcc's `-fdiagnostics-format=json`,
+    /// printed once preprocessing is done.
+    Json,
+    /// A SARIF 2.1.0 log, printed once preprocessing is done.
+    Sarif,
+}
+
+impl DiagnosticFormat {
+    pub fn from_name(name: &str) -> Option<DiagnosticFormat> {
+        match name {
+            "text" => Some(DiagnosticFormat::Text),
+            "json" => Some(DiagnosticFormat::Json),
+            "sarif" => Some(DiagnosticFormat::Sarif),
+            _ => None,
+        }
+    }
+}
+
+
+/// Quotes and escapes a string as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut ret = String::from("\"");
+
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+
+    ret.push('"');
+    ret
+}
+
+fn json_position(sources: &SourceManager, location: SourceLocation, column: usize) -> String {
+    let (file, line) = sources.presumed(location);
+    format!("{{\"file\": {}, \"line\": {}, \"column\": {}}}", json_string(&file), line, column)
+}
+
+/// A location in SARIF form, with an optional message for related locations.
+fn sarif_location(sources: &SourceManager, location: SourceLocation, length: usize, message: Option<&str>) -> String {
+    let (file, line) = sources.presumed(location);
+    let region = format!("{{\"startLine\": {}, \"startColumn\": {}, \"endColumn\": {}}}",
+                         line, location.column, location.column + length.max(1));
+    let physical = format!("\"physicalLocation\": {{\"artifactLocation\": {{\"uri\": {}}}, \"region\": {}}}",
+                           json_string(&file), region);
+
+    match message {
+        Some(message) => format!("{{{}, \"message\": {{\"text\": {}}}}}", physical, json_string(message)),
+        None => format!("{{{}}}", physical),
+    }
+}
+
+
+/// A message about the source being preprocessed, rendered the way gcc and
+/// rustc do with the offending line and a caret underneath it.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// The named warning that the diagnostic belongs to, if any.
+    pub warning: Option<Warning>,
+    pub location: Option<SourceLocation>,
+    /// How many characters starting at `location` to underline.
+    pub length: usize,
+    pub notes: Vec<Diagnostic>,
+    /// The `#include` directives that led to the file of `location`,
+    /// innermost first.
+    pub included_from: Vec<SourceLocation>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: severity,
+            message: message,
+            warning: None,
+            location: None,
+            length: 0,
+            notes: Vec::new(),
+            included_from: Vec::new(),
+        }
+    }
+
+    /// A diagnostic for a named warning.  Its severity is decided by the
+    /// warning options when it is emitted.
+    pub fn warning(warning: Warning, message: String) -> Diagnostic {
+        let mut diagnostic = Diagnostic::new(Severity::Warning, message);
+        diagnostic.warning = Some(warning);
+        diagnostic
+    }
+
+    pub fn at(mut self, location: Option<SourceLocation>, length: usize) -> Diagnostic {
+        self.location = location;
+        self.length = length;
+        self
+    }
+
+    pub fn with_note(mut self, location: Option<SourceLocation>, message: String) -> Diagnostic {
+        self.notes.push(Diagnostic::new(Severity::Note, message).at(location, 0));
+        self
+    }
+
+    pub fn render(&self, sources: &SourceManager) -> String {
+        let mut ret = String::new();
+        self.render_into(&mut ret, sources);
+        ret
+    }
+
+    /// The message followed by the option that controls it, if any.
+    fn full_message(&self) -> String {
+        match self.warning {
+            Some(warning) if self.severity >= Severity::Error => format!("{} [-Werror={}]", self.message, warning.name()),
+            Some(warning) => format!("{} [-W{}]", self.message, warning.name()),
+            None => self.message.clone(),
+        }
+    }
+
+    /// The diagnostic as a JSON object, with its notes as `children`.
+    pub fn to_json(&self, sources: &SourceManager) -> String {
+        let mut fields = vec![
+            format!("\"kind\": {}", json_string(&self.severity.to_string())),
+            format!("\"message\": {}", json_string(&self.message)),
+        ];
+
+        if let Some(warning) = self.warning {
+            fields.push(format!("\"option\": {}", json_string(&format!("-W{}", warning.name()))));
+        }
+
+        let locations = match self.location {
+            Some(location) => {
+                let caret = json_position(sources, location, location.column);
+                if self.length > 1 {
+                    let finish = json_position(sources, location, location.column + self.length - 1);
+                    format!("{{\"caret\": {}, \"finish\": {}}}", caret, finish)
+                }
+                else {
+                    format!("{{\"caret\": {}}}", caret)
+                }
+            }
+            None => String::new(),
+        };
+        fields.push(format!("\"locations\": [{}]", locations));
+
+        let included_from: Vec<String> = self.included_from.iter()
+            .map(|&include| json_position(sources, include, include.column))
+            .collect();
+        fields.push(format!("\"included-from\": [{}]", included_from.join(", ")));
+
+        let children: Vec<String> = self.notes.iter().map(|note| note.to_json(sources)).collect();
+        fields.push(format!("\"children\": [{}]", children.join(", ")));
+
+        format!("{{{}}}", fields.join(", "))
+    }
+
+    /// The diagnostic as a SARIF result, with the include stack and its notes
+    /// as related locations.
+    pub fn to_sarif(&self, sources: &SourceManager) -> String {
+        let level = match self.severity {
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error | Severity::Fatal => "error",
+        };
+        let mut fields = Vec::new();
+
+        if let Some(warning) = self.warning {
+            fields.push(format!("\"ruleId\": {}", json_string(&format!("-W{}", warning.name()))));
+        }
+        fields.push(format!("\"level\": \"{}\"", level));
+        fields.push(format!("\"message\": {{\"text\": {}}}", json_string(&self.message)));
+
+        let locations: Vec<String> = self.location.iter()
+            .map(|&location| sarif_location(sources, location, self.length, None))
+            .collect();
+        fields.push(format!("\"locations\": [{}]", locations.join(", ")));
+
+        let mut related: Vec<String> = self.included_from.iter()
+            .map(|&include| sarif_location(sources, include, 0, Some("included from here")))
+            .collect();
+        for note in self.notes.iter() {
+            if let Some(location) = note.location {
+                related.push(sarif_location(sources, location, note.length, Some(&note.message)));
+            }
+        }
+        fields.push(format!("\"relatedLocations\": [{}]", related.join(", ")));
+
+        format!("{{{}}}", fields.join(", "))
+    }
+
+    fn render_into(&self, out: &mut String, sources: &SourceManager) {
+        let location = match self.location {
+            Some(location) => location,
+            None => {
+                out.push_str(&format!("{}: {}\n", self.severity, self.full_message()));
+                return;
+            }
+        };
+
+        for (i, include) in self.included_from.iter().enumerate() {
+            let (name, line) = sources.presumed(*include);
+            let prefix = if i == 0 { "In file included from" } else { "                 from" };
+            let suffix = if i + 1 == self.included_from.len() { ':' } else { ',' };
+            out.push_str(&format!("{} {}:{}{}\n", prefix, name, line, suffix));
+        }
+
+        out.push_str(&format!("{}: {}: {}\n", sources.describe(location), self.severity, self.full_message()));
+
+        if let Some(text) = sources.line_text(location.file, location.line) {
+            // keep tabs in the padding so the caret lines up with the source
+            let padding: String = text.chars()
+                .take(location.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+
+            out.push_str(&format!("{:>5} | {}\n", location.line, text));
+            out.push_str(&format!("      | {}^{}\n", padding, "~".repeat(self.length.saturating_sub(1))));
+        }
+
+        for note in self.notes.iter() {
+            note.render_into(out, sources);
+        }
+    }
+}
+
+
+/// Puts the diagnostics from `DiagnosticEngine::render_collected` together
+/// into one JSON array or SARIF log, however many files they came from.
+/// Text diagnostics are printed as they are reported, so there is no report
+/// for them.
+pub fn report(format: DiagnosticFormat, rendered: &[String]) -> Option<String> {
+    match format {
+        DiagnosticFormat::Text => None,
+        DiagnosticFormat::Json => Some(format!("[{}]", rendered.join(",\n"))),
+        DiagnosticFormat::Sarif => {
+            Some(format!(concat!("{{\"$schema\": \"https://json.schemastore.org/sarif-2.1.0.json\", \"version\": \"2.1.0\", ",
+                                 "\"runs\": [{{\"tool\": {{\"driver\": {{\"name\": \"rcpp\", \"version\": {}}}}}, ",
+                                 "\"results\": [{}]}}]}}"),
+                         json_string(env!("CARGO_PKG_VERSION")), rendered.join(",\n")))
+        }
+    }
+}
+
+
+/// The warning options in effect from a line of a file onwards, as set by
+/// `#pragma GCC diagnostic`.
+struct StateChange {
+    file: FileId,
+    line: usize,
+    warnings: WarningOptions,
+}
+
+
+/// Prints diagnostics as they are reported and keeps count of them.
+pub struct DiagnosticEngine {
+    /// The warning options at the current position of the preprocessor.
+    warnings: WarningOptions,
+    /// The options saved by `#pragma GCC diagnostic push`.
+    saved_warnings: Vec<WarningOptions>,
+    /// Every position that the warning options changed at, so that a
+    /// diagnostic about an earlier position gets the options from there.
+    changes: Vec<StateChange>,
+    format: DiagnosticFormat,
+    /// The diagnostics waiting to be written out at the end, for the formats
+    /// that are not printed as they go.
+    collected: RefCell<Vec<Diagnostic>>,
+    /// The number of errors after which to give up, or 0 for no limit.
+    max_errors: usize,
+    error_count: Cell<usize>,
+    warning_count: Cell<usize>,
+}
+
+impl DiagnosticEngine {
+    pub fn new(warnings: WarningOptions, max_errors: usize, format: DiagnosticFormat) -> DiagnosticEngine {
+        DiagnosticEngine {
+            warnings: warnings,
+            saved_warnings: Vec::new(),
+            changes: Vec::new(),
+            format: format,
+            collected: RefCell::new(Vec::new()),
+            max_errors: max_errors,
+            error_count: Cell::new(0),
+            warning_count: Cell::new(0),
+        }
+    }
+
+    /// Records that the current warning options apply from `location` on,
+    /// which happens when they change and whenever the preprocessor moves
+    /// into or back out of an included file.
+    pub fn record_position(&mut self, location: Option<SourceLocation>) {
+        if let Some(location) = location {
+            self.changes.push(StateChange { file: location.file, line: location.line, warnings: self.warnings.clone() });
+        }
+    }
+
+    pub fn push_warnings(&mut self) {
+        self.saved_warnings.push(self.warnings.clone());
+    }
+
+    /// Restores the warning options saved by the last push, returning false
+    /// if there was no push.
+    pub fn pop_warnings(&mut self, location: Option<SourceLocation>) -> bool {
+        match self.saved_warnings.pop() {
+            Some(warnings) => {
+                self.warnings = warnings;
+                self.record_position(location);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn override_warning(&mut self, warning: Warning, level: Level, location: Option<SourceLocation>) {
+        self.warnings.override_level(warning, level);
+        self.record_position(location);
+    }
+
+    /// The warning options in effect at a location.
+    fn warnings_at(&self, location: Option<SourceLocation>) -> &WarningOptions {
+        location.and_then(|location| {
+            self.changes.iter().rev().find(|change| change.file == location.file && change.line <= location.line)
+        }).map_or(&self.warnings, |change| &change.warnings)
+    }
+
+    pub fn emit(&self, mut diagnostic: Diagnostic, sources: &SourceManager) {
+        if self.limit_reached() {
+            return;
+        }
+
+        if let Some(warning) = diagnostic.warning {
+            match self.warnings_at(diagnostic.location).severity(warning) {
+                Some(severity) => diagnostic.severity = severity,
+                None => return,
+            }
+        }
+
+        match diagnostic.severity {
+            Severity::Error | Severity::Fatal => self.error_count.set(self.error_count.get() + 1),
+            Severity::Warning => self.warning_count.set(self.warning_count.get() + 1),
+            Severity::Note => {}
+        }
+
+        if self.format != DiagnosticFormat::Text {
+            self.collected.borrow_mut().push(diagnostic);
+            return;
+        }
+
+        eprint!("{}", diagnostic.render(sources));
+
+        if self.limit_reached() {
+            eprintln!("compilation terminated due to -fmax-errors={}.", self.max_errors);
+        }
+    }
+
+    /// The diagnostics that were collected for the JSON or SARIF formats,
+    /// each written out on its own for `report` to put together.
+    pub fn render_collected(&self, sources: &SourceManager) -> Vec<String> {
+        let collected = self.collected.borrow();
+
+        match self.format {
+            DiagnosticFormat::Text => Vec::new(),
+            DiagnosticFormat::Json => collected.iter().map(|diagnostic| diagnostic.to_json(sources)).collect(),
+            DiagnosticFormat::Sarif => collected.iter().map(|diagnostic| diagnostic.to_sarif(sources)).collect(),
+        }
+    }
+
+    /// Writes out the diagnostics that were collected for the JSON or SARIF
+    /// formats.  Text diagnostics have already been printed by then.
+    pub fn finish(&self, sources: &SourceManager) -> Option<String> {
+        report(self.format, &self.render_collected(sources))
+    }
+
+    /// Whether as many errors as `-fmax-errors` allows have been reported.
+    pub fn limit_reached(&self) -> bool {
+        self.max_errors > 0 && self.error_count.get() >= self.max_errors
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.error_count.get()
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.warning_count.get()
+    }
+}
+
+impl Default for DiagnosticEngine {
+    fn default() -> DiagnosticEngine {
+        DiagnosticEngine::new(WarningOptions::new(), 0, DiagnosticFormat::Text)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use source::{SourceLocation, SourceManager};
+    use warnings::Warning;
+    use super::{report, Diagnostic, DiagnosticFormat, Severity};
+
+    /// A warning in `b.h`, which `main.c` includes, with a note in `main.c`.
+    fn included_warning() -> (SourceManager, Diagnostic) {
+        let mut sources = SourceManager::new();
+        let main = sources.add_file(Path::new("main.c"), "#define X 1\n#include \"b.h\"\n");
+        let b = sources.add_file(Path::new("b.h"), "#if ABC\n#endif\n");
+
+        let mut diagnostic = Diagnostic::warning(Warning::Undef, String::from("\"ABC\" is not defined, evaluates to 0"))
+            .at(Some(SourceLocation { file: b, line: 1, column: 5 }), 3)
+            .with_note(Some(SourceLocation { file: main, line: 1, column: 9 }), String::from("a note"));
+        diagnostic.included_from = vec![SourceLocation { file: main, line: 2, column: 2 }];
+        (sources, diagnostic)
+    }
+
+    #[test]
+    fn include_stack_is_rendered_innermost_first() {
+        let mut sources = SourceManager::new();
+        let main = sources.add_file(Path::new("main.c"), "#include \"a.h\"\n");
+        let a = sources.add_file(Path::new("a.h"), "\n#include \"b.h\"\n");
+        let b = sources.add_file(Path::new("b.h"), "#error oops\n");
+
+        let mut diagnostic = Diagnostic::new(Severity::Error, String::from("oops"))
+            .at(Some(SourceLocation { file: b, line: 1, column: 2 }), 5);
+        diagnostic.included_from = vec![SourceLocation { file: a, line: 2, column: 2 }, SourceLocation { file: main, line: 1, column: 2 }];
+
+        assert_eq!(diagnostic.render(&sources), concat!("In file included from a.h:2,\n",
+                                                        "                 from main.c:1:\n",
+                                                        "b.h:1:2: error: oops\n",
+                                                        "    1 | #error oops\n",
+                                                        "      |  ^~~~~\n"));
+    }
+
+    #[test]
+    fn json_has_gcc_layout() {
+        let (sources, diagnostic) = included_warning();
+
+        assert_eq!(diagnostic.to_json(&sources), concat!(
+            r#"{"kind": "warning", "message": "\"ABC\" is not defined, evaluates to 0", "option": "-Wundef", "#,
+            r#""locations": [{"caret": {"file": "b.h", "line": 1, "column": 5}, "finish": {"file": "b.h", "line": 1, "column": 7}}], "#,
+            r#""included-from": [{"file": "main.c", "line": 2, "column": 2}], "#,
+            r#""children": [{"kind": "note", "message": "a note", "locations": [{"caret": {"file": "main.c", "line": 1, "column": 9}}], "#,
+            r#""included-from": [], "children": []}]}"#));
+    }
+
+    #[test]
+    fn sarif_has_related_locations() {
+        let (sources, diagnostic) = included_warning();
+
+        assert_eq!(diagnostic.to_sarif(&sources), concat!(
+            r#"{"ruleId": "-Wundef", "level": "warning", "message": {"text": "\"ABC\" is not defined, evaluates to 0"}, "#,
+            r#""locations": [{"physicalLocation": {"artifactLocation": {"uri": "b.h"}, "region": {"startLine": 1, "startColumn": 5, "endColumn": 8}}}], "#,
+            r#""relatedLocations": [{"physicalLocation": {"artifactLocation": {"uri": "main.c"}, "region": {"startLine": 2, "startColumn": 2, "endColumn": 3}}, "message": {"text": "included from here"}}, "#,
+            r#"{"physicalLocation": {"artifactLocation": {"uri": "main.c"}, "region": {"startLine": 1, "startColumn": 9, "endColumn": 10}}, "message": {"text": "a note"}}]}"#));
+    }
+
+    #[test]
+    fn report_makes_one_document_for_every_file() {
+        let rendered = vec![String::from("{\"a\": 1}"), String::from("{\"b\": 2}")];
+
+        assert_eq!(report(DiagnosticFormat::Text, &rendered), None);
+        assert_eq!(report(DiagnosticFormat::Json, &rendered).unwrap(), "[{\"a\": 1},\n{\"b\": 2}]");
+
+        let sarif = report(DiagnosticFormat::Sarif, &rendered).unwrap();
+        assert_eq!(sarif.matches("\"runs\"").count(), 1);
+        assert!(sarif.ends_with("\"results\": [{\"a\": 1},\n{\"b\": 2}]}]}"));
+    }
+}
@@ -0,0 +1,87 @@
+
+use std::fmt;
+use std::io;
+use std::error::Error;
+use std::path::PathBuf;
+
+
+/// Everything that can stop the preprocessor.  The `location` of each error is
+/// the `file:line:column` that it happened on.  By the time one of these is
+/// returned it has already been reported as a `Diagnostic`.
+#[derive(Debug)]
+pub enum PreprocessError {
+    /// Reading a source file, header or `#embed` resource failed.
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// A directive is malformed, which includes conditional directives that do
+    /// not nest properly and invalid `#if` expressions.
+    DirectiveSyntax {
+        location: String,
+        message: String,
+    },
+    /// An `#include` or `#embed` names a file that is in none of the include
+    /// directories.
+    HeaderNotFound {
+        location: String,
+        name: String,
+    },
+    /// Includes were nested deeper than the configured maximum depth.
+    IncludeDepth {
+        location: String,
+        max_depth: usize,
+        include_chain: String,
+    },
+    /// An `#error` directive was reached.
+    ErrorDirective {
+        location: String,
+        message: String,
+    },
+    /// A macro could not be defined or expanded.
+    Macro {
+        location: String,
+        message: String,
+    },
+    /// As many errors as `-fmax-errors` allows have been reported.
+    TooManyErrors {
+        max_errors: usize,
+    },
+}
+
+impl fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PreprocessError::Io { ref path, ref error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            PreprocessError::DirectiveSyntax { ref location, ref message } => {
+                write!(f, "{}: error: {}", location, message)
+            }
+            PreprocessError::HeaderNotFound { ref location, ref name } => {
+                write!(f, "{}: error: {}: No such file or directory", location, name)
+            }
+            PreprocessError::IncludeDepth { ref location, max_depth, ref include_chain } => {
+                write!(f, "{}: error: #include nested too deeply (maximum depth is {})\n{}", location, max_depth, include_chain)
+            }
+            PreprocessError::ErrorDirective { ref location, ref message } => {
+                write!(f, "{}: error: #error {}", location, message)
+            }
+            PreprocessError::Macro { ref location, ref message } => {
+                write!(f, "{}: error: {}", location, message)
+            }
+            PreprocessError::TooManyErrors { max_errors } => {
+                write!(f, "compilation terminated due to -fmax-errors={}.", max_errors)
+            }
+        }
+    }
+}
+
+impl Error for PreprocessError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            PreprocessError::Io { ref error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,300 @@
+
+//! Evaluation of the integer constant expressions in `#if` and `#elif`.
+//!
+//! By the time an expression gets here its macros have been expanded and the
+//! `defined` and `__has_include` style operators have been replaced with their
+//! values, so any identifier that is left over evaluates to 0.
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Identifier(String),
+    Punct(&'static str),
+}
+
+const PUNCTUATORS: &[&str] = &[
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+    "(", ")", "!", "~", "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "?", ":",
+];
+
+fn describe(token: &Token) -> String {
+    match *token {
+        Token::Number(num) => num.to_string(),
+        Token::Identifier(ref name) => name.clone(),
+        Token::Punct(punct) => punct.to_string(),
+    }
+}
+
+fn parse_number(literal: &str) -> Result<i64, String> {
+    let digits = literal.trim_end_matches(|c| c == 'u' || c == 'U' || c == 'l' || c == 'L');
+
+    let result = if digits.starts_with("0x") || digits.starts_with("0X") {
+        u64::from_str_radix(&digits[2..], 16)
+    }
+    else if digits.starts_with("0b") || digits.starts_with("0B") {
+        u64::from_str_radix(&digits[2..], 2)
+    }
+    else if digits.len() > 1 && digits.starts_with('0') {
+        u64::from_str_radix(&digits[1..], 8)
+    }
+    else {
+        u64::from_str_radix(digits, 10)
+    };
+
+    result.map(|num| num as i64).map_err(|_| format!("invalid integer constant {:?} in preprocessor expression", literal))
+}
+
+fn parse_char(chars: &[char]) -> Result<i64, String> {
+    let value = match chars {
+        &['\\', 'n'] => '\n' as i64,
+        &['\\', 't'] => '\t' as i64,
+        &['\\', 'r'] => '\r' as i64,
+        &['\\', '0'] => 0,
+        &['\\', c] => c as i64,
+        &[c] => c as i64,
+        _ => {
+            let literal: String = chars.iter().collect();
+            return Err(format!("invalid character constant '{}' in preprocessor expression", literal));
+        }
+    };
+
+    Ok(value)
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+        }
+        else if c.is_ascii_digit() {
+            let begin = i;
+            // digit separators (C23) are allowed between the digits
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '\'') {
+                i += 1;
+            }
+            let literal: String = chars[begin..i].iter().filter(|&&c| c != '\'').collect();
+            tokens.push(Token::Number(parse_number(&literal)?));
+        }
+        else if c.is_alphabetic() || c == '_' {
+            let begin = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Identifier(chars[begin..i].iter().collect()));
+        }
+        else if c == '\'' {
+            let begin = i + 1;
+            i += 1;
+            while i < chars.len() && chars[i] != '\'' {
+                if chars[i] == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            if i >= chars.len() {
+                return Err(String::from("missing terminating ' character in preprocessor expression"));
+            }
+            tokens.push(Token::Number(parse_char(&chars[begin..i])?));
+            i += 1;
+        }
+        else {
+            let rest: String = chars[i..].iter().take(2).collect();
+            match PUNCTUATORS.iter().find(|punct| rest.starts_with(*punct)) {
+                Some(&punct) => {
+                    tokens.push(Token::Punct(punct));
+                    i += punct.len();
+                }
+                None => {
+                    return Err(format!("token {:?} is not valid in preprocessor expressions", c));
+                }
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn precedence(op: &str) -> Option<u8> {
+    let prec = match op {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | ">" | "<=" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        _ => return None,
+    };
+
+    Some(prec)
+}
+
+fn apply(op: &str, lhs: i64, rhs: i64, live: bool) -> Result<i64, String> {
+    let value = match op {
+        "||" => (lhs != 0 || rhs != 0) as i64,
+        "&&" => (lhs != 0 && rhs != 0) as i64,
+        "|" => lhs | rhs,
+        "^" => lhs ^ rhs,
+        "&" => lhs & rhs,
+        "==" => (lhs == rhs) as i64,
+        "!=" => (lhs != rhs) as i64,
+        "<" => (lhs < rhs) as i64,
+        ">" => (lhs > rhs) as i64,
+        "<=" => (lhs <= rhs) as i64,
+        ">=" => (lhs >= rhs) as i64,
+        "<<" => lhs.wrapping_shl(rhs as u32),
+        ">>" => lhs.wrapping_shr(rhs as u32),
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        "/" | "%" if rhs == 0 => {
+            // division by zero only matters in the part of the expression
+            // that actually gets evaluated, e.g. `#if 0 && 1 / 0` is fine
+            if live {
+                return Err(String::from("division by zero in preprocessor expression"));
+            }
+            0
+        }
+        "/" => lhs.wrapping_div(rhs),
+        "%" => lhs.wrapping_rem(rhs),
+        _ => unreachable!(),
+    };
+
+    Ok(value)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_punct(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(&Token::Punct(punct)) => Some(punct),
+            _ => None,
+        }
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if self.peek_punct() == Some(punct) {
+            self.pos += 1;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        if self.eat(punct) {
+            Ok(())
+        }
+        else {
+            match self.tokens.get(self.pos) {
+                Some(token) => Err(format!("expected '{}' in preprocessor expression but found '{}'", punct, describe(token))),
+                None => Err(format!("expected '{}' at end of preprocessor expression", punct)),
+            }
+        }
+    }
+
+    fn conditional(&mut self, live: bool) -> Result<i64, String> {
+        let condition = self.binary(1, live)?;
+
+        if self.eat("?") {
+            let if_true = self.conditional(live && condition != 0)?;
+            self.expect(":")?;
+            let if_false = self.conditional(live && condition == 0)?;
+            Ok(if condition != 0 { if_true } else { if_false })
+        }
+        else {
+            Ok(condition)
+        }
+    }
+
+    fn binary(&mut self, min_prec: u8, live: bool) -> Result<i64, String> {
+        let mut lhs = self.unary(live)?;
+
+        while let Some(op) = self.peek_punct() {
+            let prec = match precedence(op) {
+                Some(prec) if prec >= min_prec => prec,
+                _ => break,
+            };
+            self.pos += 1;
+
+            let rhs_live = match op {
+                "&&" => live && lhs != 0,
+                "||" => live && lhs == 0,
+                _ => live,
+            };
+            let rhs = self.binary(prec + 1, rhs_live)?;
+            lhs = apply(op, lhs, rhs, rhs_live)?;
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self, live: bool) -> Result<i64, String> {
+        if self.eat("!") {
+            Ok((self.unary(live)? == 0) as i64)
+        }
+        else if self.eat("~") {
+            Ok(!self.unary(live)?)
+        }
+        else if self.eat("-") {
+            Ok(self.unary(live)?.wrapping_neg())
+        }
+        else if self.eat("+") {
+            self.unary(live)
+        }
+        else {
+            self.primary(live)
+        }
+    }
+
+    fn primary(&mut self, live: bool) -> Result<i64, String> {
+        let token = match self.tokens.get(self.pos) {
+            Some(token) => token.clone(),
+            None => return Err(String::from("expected value at end of preprocessor expression")),
+        };
+        self.pos += 1;
+
+        match token {
+            Token::Number(num) => Ok(num),
+            Token::Identifier(ref name) if name == "true" => Ok(1),
+            Token::Identifier(_) => Ok(0),
+            Token::Punct("(") => {
+                let value = self.conditional(live)?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Token::Punct(punct) => Err(format!("expected value in preprocessor expression but found '{}'", punct)),
+        }
+    }
+}
+
+/// Evaluates a fully macro expanded `#if` expression.
+pub fn evaluate(expr: &str) -> Result<i64, String> {
+    let tokens = tokenize(expr)?;
+
+    if tokens.is_empty() {
+        return Err(String::from("#if with no expression"));
+    }
+
+    let mut parser = Parser { tokens: tokens, pos: 0 };
+    let value = parser.conditional(true)?;
+
+    match parser.tokens.get(parser.pos) {
+        Some(token) => Err(format!("missing binary operator before token '{}'", describe(token))),
+        None => Ok(value),
+    }
+}
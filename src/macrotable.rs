@@ -1,9 +1,10 @@
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use regex::{Regex, Captures};
+use source::SourceLocation;
 //use tokenizer::iter_tokens;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MacroValue {
     Constant(String),
     //Function(String, Vec<String>),
@@ -37,59 +38,116 @@ pub enum MacroValue {
 #[derive(Debug)]
 pub struct MacroTable {
     pub map: HashMap<String, MacroValue>,
+    /// Where the macros defined by `#define` are, so that diagnostics can
+    /// point at their definitions.
+    locations: HashMap<String, SourceLocation>,
+    /// The definitions saved by `#pragma push_macro`, with `None` standing
+    /// for a macro that was not defined.
+    pushed: HashMap<String, Vec<Option<(MacroValue, Option<SourceLocation>)>>>,
 }
 
 impl MacroTable {
     pub fn new() -> MacroTable {
-        MacroTable { map: HashMap::new() }
+        MacroTable { map: HashMap::new(), locations: HashMap::new(), pushed: HashMap::new() }
     }
 
-    pub fn define_from_arg(&mut self, arg: &str) {
+    pub fn define_from_arg(&mut self, arg: &str) -> Result<(), String> {
         let re = Regex::new(r"=").unwrap();
         let line = re.replace(arg, " ");
-        self.define(&line);
+        self.define(&line)
     }
 
-    pub fn define_constant(&mut self, line: &str) {
-        let re = Regex::new(r"^\s*([a-zA-Z_]+)\s*(.*)?$").unwrap();
-        let caps = re.captures(line).unwrap();
+    pub fn define_constant(&mut self, line: &str) -> Result<(), String> {
+        let re = Regex::new(r"^\s*([a-zA-Z_][a-zA-Z0-9_]*)\s*(.*)?$").unwrap();
+        let caps = re.captures(line).ok_or_else(|| format!("Macro names must be identifiers, found {:?}", line.trim()))?;
         let name = caps.get(1).unwrap().as_str();
         let contents = caps.get(2).map_or("", |mat| mat.as_str());
 
         self.map.insert(name.to_string(),
                         MacroValue::Constant(contents.to_string()));
+        Ok(())
     }
 
-    pub fn define_function(&mut self, line: &str) {
-        let re = Regex::new(r"^\s*([a-zA-Z_]+)\(([^)]*)\)\s*(.*)?$").unwrap();
+    pub fn define_function(&mut self, line: &str) -> Result<(), String> {
+        let re = Regex::new(r"^\s*([a-zA-Z_][a-zA-Z0-9_]*)\(([^)]*)\)\s*(.*)?$").unwrap();
         let arg_splitter = Regex::new(r",").unwrap();
-        let caps = re.captures(line).unwrap();
+        let caps = re.captures(line).ok_or_else(|| format!("Missing ')' in the parameter list of macro {:?}", line.trim()))?;
         let name = caps.get(1).unwrap().as_str();
         let args = caps.get(2).unwrap().as_str();
         let contents = caps.get(3).map_or("", |mat| mat.as_str());
 
         let mut arg_map = HashMap::new();
 
-        for (index, arg) in arg_splitter.split(args).map(|s| s.to_string()).enumerate() {
-            arg_map.insert(arg.trim().to_string(), index);
+        if !args.trim().is_empty() {
+            for (index, arg) in arg_splitter.split(args).map(|s| s.to_string()).enumerate() {
+                if arg_map.insert(arg.trim().to_string(), index).is_some() {
+                    return Err(format!("Duplicate macro parameter {:?} in macro {}", arg.trim(), name));
+                }
+            }
         }
 
         self.map.insert(name.to_string(),
                         MacroValue::Function(contents.to_string(), arg_map));
+        Ok(())
     }
 
-    pub fn define(&mut self, line: &str) {
-        let re = Regex::new(r"^\s*([a-zA-Z_]+)\(").unwrap();
+    pub fn define(&mut self, line: &str) -> Result<(), String> {
+        let re = Regex::new(r"^\s*([a-zA-Z_][a-zA-Z0-9_]*)\(").unwrap();
         if re.is_match(line) {
-            self.define_function(line);
+            self.define_function(line)
         }
         else {
-            self.define_constant(line);
+            self.define_constant(line)
+        }
+    }
+
+    /// Defines a macro the same way `define` does, remembering where the
+    /// definition is written.
+    pub fn define_at(&mut self, line: &str, location: Option<SourceLocation>) -> Result<(), String> {
+        self.define(line)?;
+
+        let re = Regex::new(r"^\s*([a-zA-Z_][a-zA-Z0-9_]*)").unwrap();
+        if let Some(caps) = re.captures(line) {
+            let name = caps.get(1).unwrap().as_str().to_string();
+            match location {
+                Some(location) => self.locations.insert(name, location),
+                None => self.locations.remove(&name),
+            };
         }
+        Ok(())
     }
 
     pub fn undef(&mut self, macro_name: &str) {
         self.map.remove(macro_name);
+        self.locations.remove(macro_name);
+    }
+
+    pub fn location(&self, macro_name: &str) -> Option<SourceLocation> {
+        self.locations.get(macro_name).cloned()
+    }
+
+    /// Saves the current definition of a macro, or that it is not defined.
+    pub fn push_macro(&mut self, macro_name: &str) {
+        let saved = self.map.get(macro_name).map(|value| (value.clone(), self.location(macro_name)));
+        self.pushed.entry(macro_name.to_string()).or_insert_with(Vec::new).push(saved);
+    }
+
+    /// Restores the definition saved by the last `push_macro` of a macro,
+    /// returning false when there is nothing to restore.
+    pub fn pop_macro(&mut self, macro_name: &str) -> bool {
+        let saved = match self.pushed.get_mut(macro_name).and_then(|stack| stack.pop()) {
+            Some(saved) => saved,
+            None => return false,
+        };
+
+        self.undef(macro_name);
+        if let Some((value, location)) = saved {
+            self.map.insert(macro_name.to_string(), value);
+            if let Some(location) = location {
+                self.locations.insert(macro_name.to_string(), location);
+            }
+        }
+        true
     }
 
     pub fn is_defined(&self, macro_name: &str) -> bool {
@@ -100,6 +158,20 @@ impl MacroTable {
         self.map.get(macro_name)
     }
 
+    /// The names of the macros whose replacement lists use the identifier
+    /// `name`.
+    pub fn users_of(&self, name: &str) -> HashSet<String> {
+        self.map.iter()
+            .filter(|&(_, value)| {
+                let text = match *value {
+                    MacroValue::Constant(ref text) | MacroValue::Function(ref text, _) => text,
+                };
+                text.split(|c: char| !(c.is_alphanumeric() || c == '_')).any(|word| word == name)
+            })
+            .map(|(macro_name, _)| macro_name.clone())
+            .collect()
+    }
+
     pub fn expand_constant(&self, macro_name: &str) -> Option<String> {
         self.map.get(macro_name).and_then(|value|
             match value {
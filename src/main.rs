@@ -18,32 +18,37 @@ mod preprocessor;
 mod config;
 mod macrotable;
 mod lineiter;
+mod expression;
+mod error;
+mod source;
+mod diagnostic;
+mod warnings;
 
 use preprocessor::PreProcessor;
 use config::Config;
+use error::PreprocessError;
 
 
-fn preprocess_file(file_name: &str, config: &Config) {
-    let source = {
-        let mut file = File::open(file_name).unwrap();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).expect(&format!("Unable to read {}", file_name));
-        contents
-    };
+fn run_preprocessor(cpp: &mut PreProcessor, file_name: &str, config: &Config) -> Result<(), PreprocessError> {
+    for mac in config.macro_defs.iter() {
+        cpp.define_from_arg(mac)?;
+    }
 
-    // you will need to pass this to the preprocessor later when you make it so
-    // that headers are included relative to where they are included from
-    let _path = Path::new(file_name);
+    cpp.preprocess_path(Path::new(file_name))
+}
 
+/// Preprocesses a file and prints the result, returning the number of errors
+/// that were reported along the way.  The diagnostics that are saved up for
+/// the end are added to `diagnostics`.
+fn preprocess_file(file_name: &str, config: &Config, diagnostics: &mut Vec<String>) -> Result<usize, PreprocessError> {
     let mut cpp = PreProcessor::new(config);
 
-    for mac in config.macro_defs.iter() {
-        cpp.macros.define_from_arg(mac);
-    }
-
-    cpp.preprocess_source(&source);
+    let result = run_preprocessor(&mut cpp, file_name, config);
+    diagnostics.extend(cpp.rendered_diagnostics());
+    result?;
 
     println!("{}", cpp.get_output());
+    Ok(cpp.error_count())
 }
 
 
@@ -64,8 +69,24 @@ fn main() {
 
     config.add_default_include_dirs();
 
+    // a file that fails does not stop the others from being preprocessed, and
+    // the preprocessor has already reported its errors as diagnostics
+    let mut failed = false;
+    let mut diagnostics = Vec::new();
     for file_name in config.input_files.iter() {
-        preprocess_file(file_name, &config);
+        match preprocess_file(file_name, &config, &mut diagnostics) {
+            Ok(0) => {}
+            _ => failed = true,
+        }
+    }
+
+    // the JSON and SARIF formats make one report for all of the files
+    if let Some(report) = diagnostic::report(config.diagnostics_format, &diagnostics) {
+        eprintln!("{}", report);
+    }
+
+    if failed {
+        std::process::exit(1);
     }
 }
 
@@ -1,26 +1,33 @@
 
-use std::path::Path;
-use std::io;
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::fmt;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str::pattern::{Pattern, Searcher};
 
 use regex::Regex;
 use tokenizer;
 
 use macrotable::{MacroTable, MacroValue};
-use config::Config;
+use config::{Config, Language, Standard};
 use lineiter;
+use expression;
+use error::PreprocessError;
+use source::{FileId, SourceLocation, SourceManager};
+use diagnostic::{Diagnostic, DiagnosticEngine, Severity};
+use warnings::{Level, Warning};
 
-fn expand_function_macro(text: &str, params: &HashMap<String, usize>, args: Vec<&str>) -> String {
+fn expand_function_macro(name: &str, text: &str, params: &HashMap<String, usize>, args: Vec<&str>) -> Result<String, String> {
     let mut ret = String::new();
     let mut iter = tokenizer::iter_tokens(text.to_string());
     let mut cursor = 0;
 
-    assert_eq!(params.len(), args.len(), "Error: mismatch argument and paramater length for macro");
-
-    eprintln!("expand_function_macro called with -> params: {:?} | args: {:?}", params, args);
+    if params.len() != args.len() {
+        return Err(format!("Macro {} takes {} arguments, but {} were given", name, params.len(), args.len()));
+    }
 
     while let Some((begin, end)) = iter.next() {
         let token = &text[begin..end];
@@ -35,6 +42,107 @@ fn expand_function_macro(text: &str, params: &HashMap<String, usize>, args: Vec<
 
         cursor = end;
     }
+    Ok(ret)
+}
+
+fn read_file(path: &Path) -> io::Result<String> {
+    let mut contents = String::new();
+
+    File::open(path).and_then(|mut file| file.read_to_string(&mut contents))?;
+
+    Ok(contents)
+}
+
+/// Replaces `input[begin..end]` with `text`, which is located where the text
+/// that it replaces began.
+fn replace_input(input: &mut String, locations: &mut Vec<Option<SourceLocation>>, begin: usize, end: usize, text: &str) {
+    let location = locations.get(begin).cloned().unwrap_or(None);
+    locations.splice(begin..end, std::iter::repeat(location).take(text.len()));
+    input.replace_range(begin..end, text);
+}
+
+/// Finds the parenthesized operand that starts at `from`, ignoring leading
+/// whitespace.  Returns the text between the parentheses and the offset just
+/// past the closing one.
+fn parenthesized_operand(text: &str, from: usize) -> Option<(&str, usize)> {
+    let rest = &text[from..];
+    let open = from + (rest.len() - rest.trim_start().len());
+
+    if !text[open..].starts_with('(') {
+        return None;
+    }
+
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut prev_c = ' ';
+
+    for (i, c) in text[open..].char_indices() {
+        if in_string {
+            in_string = !(c == '"' && prev_c != '\\');
+        }
+        else if c == '"' {
+            in_string = true;
+        }
+        else if c == '(' {
+            depth += 1;
+        }
+        else if c == ')' {
+            depth -= 1;
+            if depth == 0 {
+                return Some((&text[open + 1..open + i], open + i + 1));
+            }
+        }
+        prev_c = c;
+    }
+
+    None
+}
+
+/// Removes the comments from a directive line, leaving string and character
+/// literals alone.  A block comment is replaced by a single space, as it is
+/// in translation phase 3.
+fn strip_directive_comments(line: &str) -> String {
+    let mut ret = String::new();
+    let mut iter = line.chars().peekable();
+    let mut quote = None;
+
+    while let Some(c) = iter.next() {
+        match quote {
+            Some(q) => {
+                ret.push(c);
+                if c == '\\' {
+                    if let Some(escaped) = iter.next() {
+                        ret.push(escaped);
+                    }
+                }
+                else if c == q {
+                    quote = None;
+                }
+            }
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                ret.push(c);
+            }
+            None if c == '/' && iter.peek() == Some(&'/') => {
+                break;
+            }
+            None if c == '/' && iter.peek() == Some(&'*') => {
+                iter.next();
+                let mut prev_c = ' ';
+                while let Some(c) = iter.next() {
+                    if prev_c == '*' && c == '/' {
+                        break;
+                    }
+                    prev_c = c;
+                }
+                ret.push(' ');
+            }
+            None => {
+                ret.push(c);
+            }
+        }
+    }
+
     ret
 }
 
@@ -60,8 +168,346 @@ fn strip_comments(source: &str) -> String {
 
 lazy_static! {
     static ref DOUBLE_QUOTE_RE: Regex = Regex::new(r#""\s*$"#).unwrap();
+    static ref HEADER_NAME_RE: Regex = Regex::new(r###"^\s*(?:"([^"]+)"|<([^<>]+)>)\s*$"###).unwrap();
+    static ref DIRECTIVE_RE: Regex = Regex::new(r"^\s*#\s*(?:([A-Za-z_][A-Za-z0-9_]*)|([0-9]+))?\s*(.*?)\s*$").unwrap();
+    static ref LINE_OPERAND_RE: Regex = Regex::new(r#"^([0-9]+)(?:\s+"((?:[^"\\]|\\.)*)")?((?:\s+[1-4])*)\s*$"#).unwrap();
+    static ref EMBED_OPERAND_RE: Regex = Regex::new(r###"^\s*("[^"]+"|<[^<>]+>)(.*)$"###).unwrap();
+}
+
+/// The operand of an include directive, after any macro expansion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HeaderName {
+    Quoted(String),
+    Angled(String),
+}
+
+impl fmt::Display for HeaderName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeaderName::Quoted(ref name) => write!(f, "\"{}\"", name),
+            HeaderName::Angled(ref name) => write!(f, "<{}>", name),
+        }
+    }
+}
+
+impl HeaderName {
+    fn parse(s: &str) -> Option<HeaderName> {
+        let caps = HEADER_NAME_RE.captures(s)?;
+
+        if let Some(name) = caps.get(1) {
+            Some(HeaderName::Quoted(name.as_str().to_string()))
+        }
+        else {
+            caps.get(2).map(|name| HeaderName::Angled(name.as_str().to_string()))
+        }
+    }
+
+    fn path(&self) -> &Path {
+        match *self {
+            HeaderName::Quoted(ref name) | HeaderName::Angled(ref name) => Path::new(name),
+        }
+    }
+}
+
+/// Macros that are handled by the preprocessor itself rather than living in
+/// the macro table, but that `#ifdef` and `defined` should still report.
+const BUILTIN_MACROS: &[&str] = &["__LINE__", "__has_include", "__has_include_next", "__has_embed", "_Pragma"];
+
+/// The parameters that can follow the resource name of an `#embed`.
+#[derive(Default)]
+struct EmbedParameters {
+    limit: Option<String>,
+    prefix: String,
+    suffix: String,
+    if_empty: Option<String>,
 }
 
+impl EmbedParameters {
+    fn parse(text: &str) -> Result<EmbedParameters, String> {
+        let mut params = EmbedParameters::default();
+        let mut rest = text.trim_start();
+
+        while !rest.is_empty() {
+            let name_len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':')).unwrap_or(rest.len());
+            if name_len == 0 {
+                return Err(format!("Expected an #embed parameter but found {:?}", rest));
+            }
+
+            let name = &rest[..name_len];
+            let (operand, operand_end) = match parenthesized_operand(rest, name_len) {
+                Some((operand, operand_end)) => (Some(operand.trim()), operand_end),
+                None => (None, name_len),
+            };
+            // every standard parameter can also be spelled as __name__
+            let standard_name = if name.len() > 4 && name.starts_with("__") && name.ends_with("__") {
+                &name[2..name.len() - 2]
+            }
+            else {
+                name
+            };
+
+            match (standard_name, operand) {
+                ("limit", Some(operand)) => params.limit = Some(operand.to_string()),
+                ("prefix", Some(operand)) => params.prefix = operand.to_string(),
+                ("suffix", Some(operand)) => params.suffix = operand.to_string(),
+                ("if_empty", Some(operand)) => params.if_empty = Some(operand.to_string()),
+                ("limit", None) | ("prefix", None) | ("suffix", None) | ("if_empty", None) => {
+                    return Err(format!("The #embed parameter '{}' requires a parenthesized argument", name));
+                }
+                _ => {
+                    return Err(format!("Unsupported #embed parameter '{}'", name));
+                }
+            }
+
+            rest = rest[operand_end..].trim_start();
+        }
+
+        Ok(params)
+    }
+}
+
+/// Finds the byte offsets of the trigraphs in a line.  Trigraphs are never
+/// replaced, as in gcc's GNU modes, but they are worth a warning.
+fn find_trigraphs(line: &str) -> Vec<usize> {
+    line.char_indices()
+        .filter(|&(i, _)| line[i..].starts_with("??") && line[i + 2..].starts_with(|c: char| "=/'()!<>-".contains(c)))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Finds the byte offsets of any `/*` inside a block comment on a line.
+/// `in_comment` carries whether a block comment is left open from one line
+/// to the next.
+fn find_nested_comments(line: &str, in_comment: &mut bool) -> Vec<usize> {
+    let bytes = line.as_bytes();
+    let mut ret = Vec::new();
+    let mut quote = None;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        let next = bytes.get(i + 1).cloned();
+
+        if *in_comment {
+            if c == b'*' && next == Some(b'/') {
+                *in_comment = false;
+                i += 1;
+            }
+            else if c == b'/' && next == Some(b'*') {
+                ret.push(i);
+            }
+        }
+        else if let Some(q) = quote {
+            if c == b'\\' {
+                i += 1;
+            }
+            else if c == q {
+                quote = None;
+            }
+        }
+        else if c == b'"' || c == b'\'' {
+            quote = Some(c);
+        }
+        else if c == b'/' && next == Some(b'/') {
+            break;
+        }
+        else if c == b'/' && next == Some(b'*') {
+            *in_comment = true;
+            i += 1;
+        }
+        i += 1;
+    }
+
+    ret
+}
+
+/// Turns the string literal operand of `_Pragma` back into the text of the
+/// pragma, by dropping any encoding prefix and the quotes and unescaping `\"`
+/// and `\\`.
+fn destringize(literal: &str) -> Option<String> {
+    let literal = literal.trim();
+    let open = literal.find('"')?;
+
+    if !literal[..open].chars().all(|c| c.is_alphanumeric()) || literal.len() < open + 2 || !literal.ends_with('"') {
+        return None;
+    }
+
+    let mut ret = String::new();
+    let mut chars = literal[open + 1..literal.len() - 1].chars();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(escaped @ '"')) | ('\\', Some(escaped @ '\\')) => {
+                ret.push(escaped);
+                chars.next();
+            }
+            _ => ret.push(c),
+        }
+    }
+
+    Some(ret)
+}
+
+/// Splits the name off the front of a pragma's operand, for pragmas such as
+/// `pack(push, 1)` where no space follows the name.
+fn split_pragma_name(operand: &str) -> (&str, &str) {
+    let operand = operand.trim_start();
+    let name_len = operand.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(operand.len());
+    (&operand[..name_len], operand[name_len..].trim_start())
+}
+
+/// Parses the `predicate(answer)` operand of `#assert` and `#unassert`, or of
+/// `#predicate(answer)` in an `#if`, where the answer is optional.  Answers
+/// are compared with their whitespace normalized.
+fn parse_assertion(operand: &str) -> Option<(&str, Option<String>)> {
+    let (predicate, rest) = split_pragma_name(operand);
+    if predicate.is_empty() {
+        return None;
+    }
+
+    if rest.is_empty() {
+        return Some((predicate, None));
+    }
+
+    match parenthesized_operand(rest, 0) {
+        Some((answer, end)) if rest[end..].trim().is_empty() && !answer.trim().is_empty() => {
+            Some((predicate, Some(answer.split_whitespace().collect::<Vec<_>>().join(" "))))
+        }
+        _ => None,
+    }
+}
+
+/// Resolves a path for comparing files with each other, falling back to the
+/// path itself when it cannot be resolved.
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// The Levenshtein distance between two strings, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..b.len() + 1).collect();
+
+    for (i, a_c) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, &b_c) in b.iter().enumerate() {
+            let substitution = diagonal + if a_c == b_c { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Picks the candidate that `name` is most likely a misspelling of, if one is
+/// close enough to suggest.  A difference only in case is the closest of all.
+fn closest_match<I>(name: &str, candidates: I) -> Option<String>
+    where I: IntoIterator<Item = String>
+{
+    let max_distance = (name.chars().count() / 3).max(1);
+
+    candidates.into_iter()
+        .filter(|candidate| candidate != name)
+        .map(|candidate| {
+            let distance = if candidate.to_lowercase() == name.to_lowercase() { 0 } else { edit_distance(name, &candidate) };
+            (distance, candidate)
+        })
+        .filter(|&(distance, _)| distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Formats embedded bytes as a comma separated list of integer literals,
+/// sixteen to a line.
+fn format_embedded_bytes(bytes: &[u8]) -> String {
+    bytes.chunks(16)
+        .map(|chunk| chunk.iter().map(|byte| byte.to_string()).collect::<Vec<_>>().join(","))
+        .collect::<Vec<_>>()
+        .join(",\n")
+}
+
+/// A file that is currently being preprocessed.
+struct IncludeFrame {
+    path: PathBuf,
+    file: FileId,
+    /// Where the `#include` that entered the file is, for the "In file
+    /// included from" part of diagnostics.
+    included_at: Option<SourceLocation>,
+    /// Index of the include directory the file was found in, if any, so that
+    /// `__has_include_next` can continue the search from the one after it.
+    search_index: Option<usize>,
+    /// How many conditionals were already open when the file was entered,
+    /// since a file has to close every conditional that it opens.
+    conditional_depth: usize,
+    /// Whether the file is a system header, because it was found in an
+    /// `-isystem` directory or has used `#pragma GCC system_header`.
+    system_header: bool,
+}
+
+/// A stretch of the pending input that came from expanding a macro.
+struct MacroExpansion {
+    name: String,
+    begin: usize,
+    end: usize,
+}
+
+/// The macro expansions that the text being scanned by `process_input` came
+/// from, so that diagnostics can say which macros were involved.
+#[derive(Default)]
+struct ExpansionStack {
+    expansions: Vec<MacroExpansion>,
+}
+
+impl ExpansionStack {
+    /// Updates the expansions after `input[begin..end]` has been replaced by
+    /// `len` bytes of text.
+    fn replaced(&mut self, begin: usize, end: usize, len: usize) {
+        for expansion in self.expansions.iter_mut().filter(|expansion| expansion.end > begin) {
+            // an invocation whose arguments run past the end of an expansion
+            // takes the rest of the replacement along into that expansion
+            expansion.end = if expansion.end >= end { expansion.end - (end - begin) + len } else { begin + len };
+        }
+    }
+
+    /// Records that the invocation of `name` at `input[begin..end]` was
+    /// replaced by `len` bytes of expansion.
+    fn expand(&mut self, name: &str, begin: usize, end: usize, len: usize) {
+        // the scan has moved past the expansions that end before this one
+        self.expansions.retain(|expansion| expansion.end > begin);
+        self.replaced(begin, end, len);
+        self.expansions.push(MacroExpansion { name: name.to_string(), begin: begin, end: begin + len });
+    }
+
+    /// The names of the macros whose expansions contain `pos`, outermost first.
+    fn at(&self, pos: usize) -> Vec<&str> {
+        self.expansions.iter()
+            .filter(|expansion| expansion.begin <= pos && pos < expansion.end)
+            .map(|expansion| expansion.name.as_str())
+            .collect()
+    }
+}
+
+/// The pragmas whose operands gcc macro expands before passing them on to the
+/// compiler.
+const EXPANDED_PRAGMAS: &[&str] = &["pack", "omp", "acc", "redefine_extname"];
+
+/// Every directive name, for suggesting a directive when one is misspelled.
+const DIRECTIVES: &[&str] = &[
+    "include", "include_next", "embed", "define", "undef", "error", "warning", "line", "pragma",
+    "if", "ifdef", "ifndef", "elif", "elifdef", "elifndef", "else", "endif",
+    "ident", "sccs", "assert", "unassert", "import",
+];
+
+/// The most blank lines that are written to keep the output in line with the
+/// source before a linemarker is written instead, as in gcc.
+const MAX_SYNC_BLANK_LINES: usize = 8;
+
+/// The directives that still have to be processed inside skipped groups.
+const CONDITIONAL_DIRECTIVES: &[&str] = &["if", "ifdef", "ifndef", "elif", "elifdef", "elifndef", "else", "endif"];
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 enum State {
     NotYetFound,
@@ -69,171 +515,1521 @@ enum State {
     AlreadyFound,
 }
 
+/// An `#if`, `#ifdef` or `#ifndef` that has not been closed yet.
+struct Conditional {
+    state: State,
+    /// The line of the directive that opened the conditional, which is always
+    /// in the file that is current while the conditional is open.
+    opened_line: usize,
+    /// The line of the conditional's `#else`, once one has been seen.
+    else_line: Option<usize>,
+}
+
 
 pub struct PreProcessor<'b> {
     input: String,
+    /// Where each byte of `input` is in the source.  The text that replaces a
+    /// macro invocation is located at the invocation.
+    input_locations: Vec<Option<SourceLocation>>,
     output: String,
+    /// Where the output was last brought into line with the current file, as
+    /// the length of the output then and the line of the file that the next
+    /// line of output was from.  `None` when linemarkers are off, and while
+    /// text is being expanded on its own.
+    output_sync: Option<(usize, usize)>,
+    /// Output from directives that appeared while a macro invocation in
+    /// `input` was still waiting for its arguments, which is written after it.
+    deferred_output: String,
     pub macros: MacroTable,
     config: &'b Config,
-    state_stack: Vec<State>,
+    state_stack: Vec<Conditional>,
+    include_stack: Vec<IncludeFrame>,
+    /// The macros that have been expanded or tested, for -Wunused-macros.
+    used_macros: HashSet<String>,
+    /// The canonical paths of the files that `#pragma once` or `#import` has
+    /// marked to be included only once.
+    once_only: HashSet<PathBuf>,
+    /// The canonical paths of every file that has been preprocessed, which
+    /// `#import` does not include again.
+    entered_files: HashSet<PathBuf>,
+    /// The answers given to each predicate by `#assert`, for GNU assertions.
+    assertions: HashMap<String, HashSet<String>>,
+    /// The identifiers that `#pragma GCC poison` has banned, each with the
+    /// macros that were already using it, which may go on expanding to it.
+    poisoned: HashMap<String, HashSet<String>>,
+    /// The macros marked by `#pragma clang deprecated`, with their messages.
+    deprecated_macros: HashMap<String, Option<String>>,
+    /// The macros marked by `#pragma clang restrict_expansion`, with their
+    /// messages.
+    restricted_macros: HashMap<String, Option<String>>,
+    /// The identifiers that are left in the condition of an `#if` after macro
+    /// expansion, with their locations, collected while it is expanded.
+    condition_identifiers: Option<Vec<(String, (Option<SourceLocation>, usize))>>,
+    pub sources: SourceManager,
+    diagnostics: DiagnosticEngine,
+    /// The errors that preprocessing recovered from, in the order that they
+    /// were reported.
+    errors: Vec<PreprocessError>,
 }
 
 impl<'b> PreProcessor<'b> {
     pub fn new(conf: &Config) -> PreProcessor {
+        let mut macros = MacroTable::new();
+
+        for definition in ["__STDC_EMBED_NOT_FOUND__ 0", "__STDC_EMBED_FOUND__ 1", "__STDC_EMBED_EMPTY__ 2"].iter() {
+            macros.define(definition).expect("Invalid builtin macro definition");
+        }
+
         PreProcessor {
             input: String::new(),
+            input_locations: Vec::new(),
             output: String::new(),
-            macros: MacroTable::new(),
+            output_sync: None,
+            deferred_output: String::new(),
+            macros: macros,
             config: conf,
             state_stack: Vec::new(),
+            include_stack: Vec::new(),
+            used_macros: HashSet::new(),
+            once_only: HashSet::new(),
+            entered_files: HashSet::new(),
+            assertions: HashMap::new(),
+            poisoned: HashMap::new(),
+            deprecated_macros: HashMap::new(),
+            restricted_macros: HashMap::new(),
+            condition_identifiers: None,
+            sources: SourceManager::new(),
+            diagnostics: DiagnosticEngine::new(conf.warnings.clone(), conf.max_errors, conf.diagnostics_format),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Defines a macro from a `-D` command line argument.
+    pub fn define_from_arg(&mut self, arg: &str) -> Result<(), PreprocessError> {
+        self.macros.define_from_arg(arg).map_err(|message| {
+            self.emit(Diagnostic::new(Severity::Error, format!("{} (in -D{})", message, arg)));
+            PreprocessError::Macro { location: format!("<command line> -D{}", arg), message: message }
+        })
+    }
+
+    /// The diagnostics that the JSON and SARIF formats save up until
+    /// preprocessing is done, each written out on its own so that those of
+    /// several files can go into one `diagnostic::report`.
+    pub fn rendered_diagnostics(&self) -> Vec<String> {
+        self.diagnostics.render_collected(&self.sources)
+    }
+
+    /// The number of errors that have been reported so far.
+    pub fn error_count(&self) -> usize {
+        self.diagnostics.error_count()
+    }
+
+    /// Keeps an error that has been reported and recovered from, carrying on
+    /// with `default` in place of the result, unless there have been too many
+    /// errors to carry on at all.
+    fn recover<T>(&mut self, result: Result<T, PreprocessError>, default: T) -> Result<T, PreprocessError> {
+        match result {
+            Err(err @ PreprocessError::TooManyErrors { .. }) => Err(err),
+            Err(err) => {
+                self.errors.push(err);
+                Ok(default)
+            }
+            Ok(value) => Ok(value),
+        }
+    }
+
+    /// The errors that preprocessing has recovered from so far.  Errors that
+    /// have no `PreprocessError` of their own, such as an unterminated
+    /// conditional, are only counted by `error_count`.
+    pub fn errors(&self) -> &[PreprocessError] {
+        &self.errors
+    }
+
+    /// The directories to search for a header, in order, along with their
+    /// index among the include directories.  The directory of the current file
+    /// comes first when the header name was written in quotes.  When `next` is
+    /// set the search continues from the include directory after the one that
+    /// the current file was found in, for `#include_next`.  The system include
+    /// directories are searched after the `-I` ones, and are numbered after
+    /// them.
+    fn search_dirs(&self, header: &HeaderName, next: bool) -> Vec<(PathBuf, Option<usize>)> {
+        let current = self.include_stack.last();
+        let resume_index = current.and_then(|frame| frame.search_index).filter(|_| next);
+        let mut dirs = Vec::new();
+
+        if let (&HeaderName::Quoted(_), None) = (header, resume_index) {
+            if let Some(dir) = current.and_then(|frame| frame.path.parent()) {
+                dirs.push((dir.to_path_buf(), None));
+            }
+        }
+
+        let start = resume_index.map_or(0, |index| index + 1);
+
+        let include_dirs = self.config.include_dirs.iter().chain(self.config.system_include_dirs.iter());
+        for (index, include_dir) in include_dirs.enumerate().skip(start) {
+            dirs.push((include_dir.clone(), Some(index)));
+        }
+
+        dirs
+    }
+
+    fn find_header(&self, header: &HeaderName, next: bool) -> Option<(PathBuf, Option<usize>)> {
+        self.search_dirs(header, next).into_iter()
+            .map(|(dir, index)| (dir.join(header.path()), index))
+            .find(|&(ref full_path, _)| full_path.is_file())
+    }
+
+    /// Looks through the directories that were searched for a header with a
+    /// name close to `header`'s, to suggest when it cannot be found.
+    fn suggest_header(&self, header: &HeaderName, dirs: &[PathBuf]) -> Option<HeaderName> {
+        let path = header.path();
+        let file_name = path.file_name()?.to_str()?;
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let mut candidates = Vec::new();
+
+        for dir in dirs.iter() {
+            if let Ok(entries) = fs::read_dir(dir.join(parent)) {
+                candidates.extend(entries.filter_map(|entry| entry.ok()?.file_name().into_string().ok()));
+            }
+        }
+
+        let suggestion = parent.join(closest_match(file_name, candidates)?).display().to_string();
+        match *header {
+            HeaderName::Quoted(_) => Some(HeaderName::Quoted(suggestion)),
+            HeaderName::Angled(_) => Some(HeaderName::Angled(suggestion)),
+        }
+    }
+
+    /// Finds and reads a header, or returns `None` when the header has been
+    /// included already and should only be included once, which is always
+    /// the case for `#import` when `import` is set.
+    fn get_header_contents(&mut self, header: &HeaderName, next: bool, import: bool, line_number: usize) -> Result<Option<(IncludeFrame, String)>, PreprocessError> {
+        let (full_path, search_index) = match self.find_header(header, next) {
+            Some(found) => found,
+            None => return Err(self.header_not_found(header, next, line_number)),
+        };
+
+        let canonical = canonical_path(&full_path);
+        if self.once_only.contains(&canonical) || (import && self.entered_files.contains(&canonical)) {
+            return Ok(None);
+        }
+
+        let contents = read_file(&full_path).map_err(|err| self.io_error(&full_path, err, line_number))?;
+        let file = self.sources.add_file(&full_path, &contents);
+        // a header found next to the current file is a system header when the
+        // current file is one
+        let system_header = match search_index {
+            Some(index) => index >= self.config.include_dirs.len(),
+            None => self.include_stack.last().map_or(false, |frame| frame.system_header),
+        };
+        let frame = IncludeFrame {
+            path: full_path,
+            file: file,
+            included_at: None,
+            search_index: search_index,
+            conditional_depth: 0,
+            system_header: system_header,
+        };
+        Ok(Some((frame, contents)))
+    }
+
+    /// Reports a diagnostic, adding the chain of includes that led to the
+    /// current file when it has a location.  Warnings in system headers are
+    /// left out.
+    fn emit(&self, mut diagnostic: Diagnostic) {
+        let is_warning = diagnostic.warning.is_some() || diagnostic.severity == Severity::Warning;
+        if is_warning && diagnostic.location.map_or(false, |location| self.is_system_header(location.file)) {
+            return;
+        }
+
+        if diagnostic.location.is_some() {
+            diagnostic.included_from = self.include_stack.iter().rev().filter_map(|frame| frame.included_at).collect();
+        }
+        self.diagnostics.emit(diagnostic, &self.sources);
+    }
+
+    /// Whether a file that is being preprocessed is a system header.
+    fn is_system_header(&self, file: FileId) -> bool {
+        self.include_stack.iter().any(|frame| frame.file == file && frame.system_header)
+    }
+
+    /// A location in the current file, or `None` when preprocessing text that
+    /// does not belong to a file.
+    fn location(&self, line_number: usize, column: usize) -> Option<SourceLocation> {
+        self.include_stack.last().map(|frame| SourceLocation { file: frame.file, line: line_number, column: column })
+    }
+
+    /// The location and length of the name of the directive on a line of the
+    /// current file, which is where diagnostics about the directive point.
+    fn directive_location(&self, line_number: usize) -> (Option<SourceLocation>, usize) {
+        let text = match self.include_stack.last() {
+            Some(frame) => self.sources.line_text(frame.file, line_number).unwrap_or(""),
+            None => return (None, 0),
+        };
+        let chars: Vec<char> = text.chars().collect();
+
+        let hash = chars.iter().position(|&c| c == '#').unwrap_or(0);
+        let mut begin = hash + 1;
+        while begin < chars.len() && chars[begin].is_whitespace() {
+            begin += 1;
+        }
+        let mut end = begin;
+        while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_') {
+            end += 1;
+        }
+
+        if end > begin {
+            (self.location(line_number, begin + 1), end - begin)
+        }
+        else {
+            (self.location(line_number, hash + 1), 1)
+        }
+    }
+
+    /// The location and length of the first appearance of the identifier
+    /// `token` on a line of the current file, for when there is nothing better
+    /// to go on.  A token that is not on the line points at its start instead.
+    fn token_location(&self, line_number: usize, token: &str) -> (Option<SourceLocation>, usize) {
+        let text = match self.include_stack.last() {
+            Some(frame) => self.sources.line_text(frame.file, line_number).unwrap_or(""),
+            None => return (None, 0),
+        };
+        let is_ident_char = |c: char| c.is_alphanumeric() || c == '_';
+
+        let found = text.match_indices(token).find(|&(begin, _)| {
+            let before = text[..begin].chars().next_back();
+            let after = text[begin + token.len()..].chars().next();
+            !before.map_or(false, &is_ident_char) && !after.map_or(false, &is_ident_char)
+        });
+
+        match found {
+            Some((begin, _)) => (self.location(line_number, text[..begin].chars().count() + 1), token.chars().count()),
+            None => (self.location(line_number, 1), 0),
+        }
+    }
+
+    /// The location and length of the token at `input[begin..end]`, where
+    /// `stack` lists the expansions that it came out of, outermost first.
+    fn input_token_location(&self, line_number: usize, begin: usize, end: usize, stack: &[&str]) -> (Option<SourceLocation>, usize) {
+        // a token that came out of an expansion is not in the source, so point
+        // at the invocation of the outermost macro instead
+        let name = stack.first().cloned().unwrap_or(&self.input[begin..end]);
+
+        match self.input_locations.get(begin) {
+            Some(&Some(location)) => (Some(location), name.chars().count()),
+            _ => self.token_location(line_number, name),
+        }
+    }
+
+    /// A diagnostic at `location` about a macro as it is being expanded, with
+    /// an "expanded from macro" note for each expansion in `stack`, which
+    /// lists the expansions that the macro came out of, outermost first.
+    fn expansion_diagnostic(&self, severity: Severity, message: String, (location, length): (Option<SourceLocation>, usize), stack: &[&str]) -> Diagnostic {
+        let mut diagnostic = Diagnostic::new(severity, message).at(location, length);
+
+        for expanded in stack.iter().rev() {
+            let note = Diagnostic::new(Severity::Note, format!("expanded from macro '{}'", expanded));
+            diagnostic.notes.push(note.at(self.macros.location(expanded), expanded.len()));
+        }
+        diagnostic
+    }
+
+    fn describe_location(&self, location: Option<SourceLocation>, line_number: usize) -> String {
+        match location {
+            Some(location) => self.sources.describe(location),
+            None => format!("line {}", line_number),
+        }
+    }
+
+    /// Reports an error about the directive on `line_number` and returns the
+    /// description of its location for the matching `PreprocessError`.
+    fn report_directive_error(&self, line_number: usize, message: &str, notes: Vec<Diagnostic>) -> String {
+        let (location, length) = self.directive_location(line_number);
+        let mut diagnostic = Diagnostic::new(Severity::Error, message.to_string()).at(location, length);
+        diagnostic.notes = notes;
+        self.emit(diagnostic);
+
+        self.describe_location(location, line_number)
+    }
+
+    /// Reports a header that could not be found, along with a header it
+    /// could have been meant to be and the directories that were searched.
+    fn header_not_found(&self, header: &HeaderName, next: bool, line_number: usize) -> PreprocessError {
+        let name = header.path().display().to_string();
+        let dirs: Vec<PathBuf> = self.search_dirs(header, next).into_iter().map(|(dir, _)| dir).collect();
+        let mut notes = Vec::new();
+
+        if let Some(suggestion) = self.suggest_header(header, &dirs) {
+            notes.push(self.directive_note(line_number, &format!("did you mean {}?", suggestion)));
+        }
+        for dir in dirs.iter() {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir.as_path() };
+            notes.push(Diagnostic::new(Severity::Note, format!("searched in {}", dir.display())));
+        }
+
+        let location = self.report_directive_error(line_number, &format!("{}: No such file or directory", name), notes);
+
+        PreprocessError::HeaderNotFound { location: location, name: name }
+    }
+
+    fn io_error(&self, path: &Path, err: io::Error, line_number: usize) -> PreprocessError {
+        self.report_directive_error(line_number, &format!("{}: {}", path.display(), err), Vec::new());
+
+        PreprocessError::Io { path: path.to_path_buf(), error: err }
+    }
+
+    fn syntax_error(&self, line_number: usize, message: String) -> PreprocessError {
+        let location = self.report_directive_error(line_number, &message, Vec::new());
+
+        PreprocessError::DirectiveSyntax { location: location, message: message }
+    }
+
+    fn macro_error(&self, line_number: usize, message: String) -> PreprocessError {
+        let location = self.report_directive_error(line_number, &message, Vec::new());
+
+        PreprocessError::Macro { location: location, message: message }
+    }
+
+    /// Runs `text` through macro expansion on its own, without disturbing any
+    /// input or output that is already pending.
+    fn expand_text(&mut self, text: &str, line_number: usize) -> Result<String, PreprocessError> {
+        let column = self.text_column(line_number, text);
+        self.expand_text_at(text, line_number, column)
+    }
+
+    /// Does what `expand_text` does for `text` that starts at `column` of the
+    /// line.
+    fn expand_text_at(&mut self, text: &str, line_number: usize, column: Option<usize>) -> Result<String, PreprocessError> {
+        let saved_input = std::mem::replace(&mut self.input, String::new());
+        let saved_locations = std::mem::replace(&mut self.input_locations, Vec::new());
+        let saved_output = std::mem::replace(&mut self.output, String::new());
+        let saved_sync = self.output_sync.take();
+        let saved_deferred = std::mem::replace(&mut self.deferred_output, String::new());
+
+        let result = self.feed_text(text, line_number, column);
+
+        let mut expanded = std::mem::replace(&mut self.output, saved_output);
+        expanded.push_str(&std::mem::replace(&mut self.input, saved_input));
+        self.input_locations = saved_locations;
+        self.output_sync = saved_sync;
+        self.deferred_output = saved_deferred;
+        result.map(|_| expanded.trim().to_string())
+    }
+
+    /// The column that `text` starts at on a line of the current file, if it
+    /// is on the line as it is.
+    fn text_column(&self, line_number: usize, text: &str) -> Option<usize> {
+        let line = self.sources.line_text(self.include_stack.last()?.file, line_number)?;
+        line.find(text).map(|begin| line[..begin].chars().count() + 1)
+    }
+
+    /// Parses the operand of an include, macro expanding it first if it is not
+    /// already written as a `"..."` or `<...>` header name.
+    fn parse_include_operand(&mut self, operand: &str, line_number: usize) -> Result<Option<HeaderName>, PreprocessError> {
+        if let Some(header) = HeaderName::parse(operand) {
+            return Ok(Some(header));
+        }
+
+        let expanded = self.expand_text(operand, line_number)?;
+        match HeaderName::parse(&expanded) {
+            // the tokens of a <...> header name are joined the way gcc joins
+            // them, with each run of whitespace between them made a single
+            // space and none before the >
+            Some(HeaderName::Angled(name)) => {
+                let mut joined = name.split_whitespace().collect::<Vec<_>>().join(" ");
+                if name.starts_with(char::is_whitespace) {
+                    joined.insert(0, ' ');
+                }
+                Ok(Some(HeaderName::Angled(joined)))
+            }
+            header => Ok(header),
+        }
+    }
+
+    /// Includes a header, continuing the search from the include directory
+    /// after the current file's for `#include_next` when `next` is set, and
+    /// marking it to be included only once for `#import` when `once` is set.
+    fn include_source(&mut self, s: &str, next: bool, once: bool, line_number: usize) -> Result<(), PreprocessError> {
+        let header = match self.parse_include_operand(s, line_number)? {
+            Some(header) => header,
+            None => {
+                let message = format!("Ill formatted include directive, expected \"FILENAME\" or <FILENAME> but found {:?}", s.trim());
+                return Err(self.syntax_error(line_number, message));
+            }
+        };
+
+        // the main file is on the include stack as well, but it is not nested
+        if self.include_stack.len() > self.config.max_include_depth {
+            let max_depth = self.config.max_include_depth;
+            let message = format!("#include nested too deeply (maximum depth is {})", max_depth);
+            return Err(PreprocessError::IncludeDepth {
+                location: self.report_directive_error(line_number, &message, Vec::new()),
+                max_depth: max_depth,
+                include_chain: self.include_chain(),
+            });
+        }
+
+        if next && self.include_stack.len() <= 1 {
+            let (location, length) = self.directive_location(line_number);
+            self.emit(Diagnostic::warning(Warning::IncludeNext, String::from("#include_next in primary source file")).at(location, length));
+        }
+
+        let (mut frame, source) = match self.get_header_contents(&header, next, once, line_number)? {
+            Some(contents) => contents,
+            None => return Ok(()),
+        };
+        frame.included_at = self.directive_location(line_number).0;
+        if once {
+            self.once_only.insert(canonical_path(&frame.path));
+        }
+
+        self.preprocess_frame(frame, &source)
+    }
+
+    /// Describes the current chain of includes, innermost file first, in the
+    /// same "included from" form that gcc uses.
+    fn include_chain(&self) -> String {
+        let mut chain = String::new();
+        let mut iter = self.include_stack.iter().rev();
+
+        if let Some(innermost) = iter.next() {
+            chain.push_str(&format!("    in {}", innermost.path.display()));
+        }
+        for includer in iter {
+            chain.push_str(&format!("\n    included from {}", includer.path.display()));
+        }
+        chain
+    }
+
+    /// Whether a macro is defined, which counts as a use of the macro for
+    /// -Wunused-macros.  Testing a poisoned identifier is an error as well.
+    fn is_defined(&mut self, macro_name: &str, line_number: usize) -> bool {
+        if self.poisoned.contains_key(macro_name) {
+            self.poisoned_use(macro_name, self.token_location(line_number, macro_name));
+        }
+        self.used_macros.insert(macro_name.to_string());
+        self.macros.is_defined(macro_name) || BUILTIN_MACROS.contains(&macro_name)
+    }
+
+    /// Reports the use of an identifier that `#pragma GCC poison` has banned.
+    fn poisoned_use(&self, name: &str, (location, length): (Option<SourceLocation>, usize)) {
+        self.emit(Diagnostic::new(Severity::Error, format!("attempt to use poisoned \"{}\"", name)).at(location, length));
+    }
+
+    /// Records an identifier that expansion leaves in the condition of an
+    /// `#if`, for -Wundef.
+    fn collect_condition_identifier(&mut self, line_number: usize, begin: usize, end: usize, stack: &[&str]) {
+        if self.condition_identifiers.is_none() || !self.input[begin..end].starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            return;
+        }
+
+        let location = self.input_token_location(line_number, begin, end, stack);
+        let name = self.input[begin..end].to_string();
+        if let Some(identifiers) = self.condition_identifiers.as_mut() {
+            identifiers.push((name, location));
+        }
+    }
+
+    /// Warns about the expansion of a macro that has been marked by `#pragma
+    /// clang deprecated`, or by `#pragma clang restrict_expansion` when the
+    /// expansion is in a header.  The invocation is at `location`, and `stack`
+    /// lists the expansions that it came out of.
+    fn check_macro_annotations(&self, name: &str, location: (Option<SourceLocation>, usize), stack: &[&str]) {
+        let in_header = self.include_stack.len() > 1;
+        let annotations = [
+            (Warning::DeprecatedPragma, "deprecated", self.deprecated_macros.get(name)),
+            (Warning::RestrictExpansion, "unsafe for use in headers", self.restricted_macros.get(name).filter(|_| in_header)),
+        ];
+
+        for &(warning, marked, annotation) in annotations.iter() {
+            let message = match annotation {
+                Some(&Some(ref message)) => format!("macro '{}' has been marked as {}: {}", name, marked, message),
+                Some(&None) => format!("macro '{}' has been marked as {}", name, marked),
+                None => continue,
+            };
+
+            let mut diagnostic = self.expansion_diagnostic(Severity::Warning, message, location, stack);
+            diagnostic.warning = Some(warning);
+            self.emit(diagnostic);
+        }
+    }
+
+    /// Warns about a macro that was defined in the main file but never used.
+    fn check_unused_macro(&self, macro_name: &str, main_file: FileId) {
+        if self.used_macros.contains(macro_name) {
+            return;
+        }
+
+        if let Some(location) = self.macros.location(macro_name).filter(|location| location.file == main_file) {
+            let message = format!("macro \"{}\" is not used", macro_name);
+            self.emit(Diagnostic::warning(Warning::UnusedMacros, message).at(Some(location), macro_name.len()));
+        }
+    }
+
+    /// Checks every macro that is still defined at the end of the main file
+    /// for -Wunused-macros.
+    fn check_unused_macros(&self, main_file: FileId) {
+        let mut names: Vec<(usize, usize, &str)> = self.macros.map.keys()
+            .filter_map(|name| self.macros.location(name).map(|location| (location.line, location.column, name.as_str())))
+            .collect();
+        names.sort();
+
+        for &(_, _, name) in names.iter() {
+            self.check_unused_macro(name, main_file);
+        }
+    }
+
+    /// Replaces the `defined`, `__has_include`, `__has_include_next` and
+    /// `__has_embed` operators in an `#if` condition with their values.  This
+    /// happens before macro expansion so that their operands are not expanded.
+    fn replace_condition_operators(&mut self, condition: &str, line_number: usize) -> Result<String, PreprocessError> {
+        let mut ret = String::new();
+        let mut cursor = 0;
+        let mut iter = tokenizer::iter_tokens(condition.to_string());
+
+        while let Some((begin, end)) = iter.next() {
+            let value = match &condition[begin..end] {
+                "defined" => {
+                    if let Some((operand, operand_end)) = parenthesized_operand(condition, end) {
+                        Some((self.is_defined(operand.trim(), line_number) as i64, operand_end))
+                    }
+                    else if let Some((name_begin, name_end)) = iter.next() {
+                        Some((self.is_defined(&condition[name_begin..name_end], line_number) as i64, name_end))
+                    }
+                    else {
+                        return Err(self.syntax_error(line_number, String::from("Operator \"defined\" requires an identifier")));
+                    }
+                }
+                name @ "__has_include" | name @ "__has_include_next" => {
+                    let (operand, operand_end) = match parenthesized_operand(condition, end) {
+                        Some(operand) => operand,
+                        None => return Err(self.syntax_error(line_number, format!("Missing '(' after {}", name))),
+                    };
+                    let header = match self.parse_include_operand(operand, line_number)? {
+                        Some(header) => header,
+                        None => {
+                            let message = format!("{} requires a header name but found {:?}", name, operand.trim());
+                            return Err(self.syntax_error(line_number, message));
+                        }
+                    };
+                    Some((self.find_header(&header, name == "__has_include_next").is_some() as i64, operand_end))
+                }
+                "__has_embed" => {
+                    let (operand, operand_end) = match parenthesized_operand(condition, end) {
+                        Some(operand) => operand,
+                        None => return Err(self.syntax_error(line_number, String::from("Missing '(' after __has_embed"))),
+                    };
+                    Some((self.has_embed(operand, line_number)?, operand_end))
+                }
+                "#" if self.config.gnu_extensions => {
+                    // a GNU assertion, #predicate or #predicate(answer)
+                    let (name_begin, name_end) = match iter.next() {
+                        Some(name) => name,
+                        None => return Err(self.syntax_error(line_number, String::from("assertion without predicate"))),
+                    };
+                    let assertion_end = parenthesized_operand(condition, name_end).map_or(name_end, |(_, operand_end)| operand_end);
+                    let (predicate, answer) = match parse_assertion(&condition[name_begin..assertion_end]) {
+                        Some(assertion) => assertion,
+                        None => {
+                            let message = format!("invalid assertion {:?} in #if", &condition[begin..assertion_end]);
+                            return Err(self.syntax_error(line_number, message));
+                        }
+                    };
+                    Some((self.is_asserted(predicate, answer.as_ref()) as i64, assertion_end))
+                }
+                _ => None,
+            };
+
+            if let Some((value, value_end)) = value {
+                // the value takes up as much room as the operator did, so that
+                // the rest of the condition stays at the same columns
+                ret.push_str(&condition[cursor..begin]);
+                ret.push_str(&format!("{:<1$}", value, condition[begin..value_end].chars().count()));
+                cursor = value_end;
+                iter.set_cursor(value_end);
+            }
+        }
+
+        ret.push_str(&condition[cursor..]);
+        Ok(ret)
+    }
+
+    /// Whether `#assert` has given a predicate the answer, or any answer when
+    /// there isn't one.
+    fn is_asserted(&self, predicate: &str, answer: Option<&String>) -> bool {
+        match (self.assertions.get(predicate), answer) {
+            (Some(answers), Some(answer)) => answers.contains(answer),
+            (Some(answers), None) => !answers.is_empty(),
+            (None, _) => false,
+        }
+    }
+
+    /// Handles `#assert predicate(answer)`, and `#unassert predicate(answer)`
+    /// or `#unassert predicate`, which removes every answer.
+    fn change_assertion(&mut self, directive: &str, operand: &str, line_number: usize) {
+        let assertion = parse_assertion(operand).filter(|&(_, ref answer)| answer.is_some() || directive == "unassert");
+        let (predicate, answer) = match assertion {
+            Some(assertion) => assertion,
+            None => {
+                self.report_error(line_number, &format!("expected predicate(answer) after #{} but found {:?}", directive, operand));
+                return;
+            }
+        };
+
+        match (directive, answer) {
+            ("assert", Some(answer)) => {
+                self.assertions.entry(predicate.to_string()).or_insert_with(HashSet::new).insert(answer);
+            }
+            (_, Some(answer)) => {
+                if let Some(answers) = self.assertions.get_mut(predicate) {
+                    answers.remove(&answer);
+                }
+            }
+            (_, None) => {
+                self.assertions.remove(predicate);
+            }
+        }
+    }
+
+    /// Reports a GNU directive used without GNU extensions, returning whether
+    /// the directive can be carried out.
+    fn check_gnu_directive(&self, directive: &str, line_number: usize) -> bool {
+        if !self.config.gnu_extensions {
+            let message = format!("#{} is a GNU extension, but the preprocessor is in {} mode", directive, self.config.std_name());
+            self.report_error(line_number, &message);
+        }
+        self.config.gnu_extensions
+    }
+
+    /// Evaluates a preprocessor constant expression, such as the condition of
+    /// an `#if` or the `limit` of an `#embed`.
+    fn evaluate_expression(&mut self, expr: &str, line_number: usize) -> Result<i64, PreprocessError> {
+        let replaced = self.replace_condition_operators(expr, line_number)?;
+        let column = self.text_column(line_number, expr);
+
+        self.condition_identifiers = Some(Vec::new());
+        let expanded = self.expand_text_at(&replaced, line_number, column);
+        let mut located = self.condition_identifiers.take().unwrap_or_default().into_iter();
+        let mut expanded = expanded?;
+        let mut identifiers = Vec::new();
+
+        for (begin, end) in tokenizer::iter_tokens(expanded.clone()) {
+            let token = &expanded[begin..end];
+            if token.starts_with(|c: char| c.is_alphabetic() || c == '_') && token != "true" && token != "false" {
+                // an identifier that was pasted together by ## was not seen
+                // by the expansion, so it is looked for on the line instead
+                let location = match located.find(|&(ref name, _)| name == token) {
+                    Some((_, location)) => location,
+                    None => self.token_location(line_number, token),
+                };
+                identifiers.push((token.to_string(), location));
+            }
+        }
+
+        if identifiers.iter().any(|&(ref name, _)| name == "defined") {
+            let (location, length) = self.directive_location(line_number);
+            let message = String::from("this use of \"defined\" may not be portable");
+            self.emit(Diagnostic::warning(Warning::ExpansionToDefined, message).at(location, length));
+            expanded = self.replace_condition_operators(&expanded, line_number)?;
+        }
+        else {
+            for &(ref name, (location, length)) in identifiers.iter() {
+                let message = format!("\"{}\" is not defined, evaluates to 0", name);
+                self.emit(Diagnostic::warning(Warning::Undef, message).at(location, length));
+            }
+        }
+
+        expression::evaluate(&expanded).map_err(|message| {
+            self.syntax_error(line_number, format!("Invalid preprocessor expression {:?}: {}", expr.trim(), message))
+        })
+    }
+
+    fn evaluate_condition(&mut self, condition: &str, line_number: usize) -> Result<bool, PreprocessError> {
+        Ok(self.evaluate_expression(condition, line_number)? != 0)
+    }
+
+    /// Splits the operand of an `#embed`, `__has_embed` or `#pragma GCC dependency`
+    /// into the resource name and the text after it, macro expanding the operand
+    /// first if it does not start with a header name.
+    fn split_embed_operand(&mut self, operand: &str, line_number: usize) -> Result<Option<(HeaderName, String)>, PreprocessError> {
+        let operand = if EMBED_OPERAND_RE.is_match(operand) {
+            operand.to_string()
+        }
+        else {
+            self.expand_text(operand, line_number)?
+        };
+
+        Ok(EMBED_OPERAND_RE.captures(&operand).and_then(|caps| {
+            let header = HeaderName::parse(caps.get(1).unwrap().as_str())?;
+            Some((header, caps.get(2).unwrap().as_str().to_string()))
+        }))
+    }
+
+    /// Applies the `limit` parameter of an `#embed` to the length of the resource.
+    fn embed_length(&mut self, params: &EmbedParameters, file_len: usize, line_number: usize) -> Result<usize, PreprocessError> {
+        match params.limit {
+            Some(ref limit) => {
+                let limit = self.evaluate_expression(limit, line_number)?;
+                if limit < 0 {
+                    return Err(self.syntax_error(line_number, format!("The #embed limit must not be negative, but it is {}", limit)));
+                }
+                Ok(file_len.min(limit as usize))
+            }
+            None => Ok(file_len),
+        }
+    }
+
+    /// The value of `__has_embed`: one of `__STDC_EMBED_NOT_FOUND__`,
+    /// `__STDC_EMBED_FOUND__` or `__STDC_EMBED_EMPTY__`.
+    fn has_embed(&mut self, operand: &str, line_number: usize) -> Result<i64, PreprocessError> {
+        let (header, param_text) = match self.split_embed_operand(operand, line_number)? {
+            Some(split) => split,
+            None => {
+                let message = format!("__has_embed requires a resource name but found {:?}", operand.trim());
+                return Err(self.syntax_error(line_number, message));
+            }
+        };
+        let params = match EmbedParameters::parse(&param_text) {
+            Ok(params) => params,
+            Err(_) => return Ok(0),
+        };
+
+        match self.find_header(&header, false).and_then(|(path, _)| fs::metadata(path).ok()) {
+            Some(metadata) => {
+                let length = self.embed_length(&params, metadata.len() as usize, line_number)?;
+                Ok(if length == 0 { 2 } else { 1 })
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn embed_resource(&mut self, s: &str, line_number: usize) -> Result<(), PreprocessError> {
+        let (header, param_text) = match self.split_embed_operand(s, line_number)? {
+            Some(split) => split,
+            None => {
+                let message = format!("Ill formatted embed directive, expected \"FILENAME\" or <FILENAME> but found {:?}", s.trim());
+                return Err(self.syntax_error(line_number, message));
+            }
+        };
+        let params = EmbedParameters::parse(&param_text).map_err(|message| self.syntax_error(line_number, message))?;
+
+        let path = match self.find_header(&header, false) {
+            Some((path, _)) => path,
+            None => return Err(self.header_not_found(&header, false, line_number)),
+        };
+        let mut contents = fs::read(&path).map_err(|err| self.io_error(&path, err, line_number))?;
+
+        let length = self.embed_length(&params, contents.len(), line_number)?;
+        contents.truncate(length);
+
+        let mut embedded = String::new();
+        if contents.is_empty() {
+            if let Some(ref if_empty) = params.if_empty {
+                embedded.push_str(if_empty);
+                embedded.push('\n');
+            }
+        }
+        else {
+            let data = format_embedded_bytes(&contents);
+            for part in [&params.prefix, &data, &params.suffix].iter() {
+                if !part.is_empty() {
+                    embedded.push_str(part);
+                    embedded.push('\n');
+                }
+            }
+        }
+        self.write_directive_output(&embedded, line_number);
+
+        Ok(())
+    }
+
+    /// Writes the output of the directive on `line_number`.  A macro
+    /// invocation that is still waiting for its arguments comes before the
+    /// directive in the source, so the directive's output waits until the
+    /// invocation has been expanded.
+    fn write_directive_output(&mut self, text: &str, line_number: usize) {
+        if self.input.is_empty() {
+            self.sync_output(line_number);
+            self.output.push_str(text);
+        }
+        else {
+            self.deferred_output.push_str(text);
+        }
+    }
+
+    /// Maps a physical line number of the current file to the line number
+    /// that `#line` directives say it has.
+    fn presumed_line(&self, line_number: usize) -> usize {
+        match self.location(line_number, 1) {
+            Some(location) => self.sources.presumed(location).1,
+            None => line_number,
+        }
+    }
+
+    /// Reports an error in the current file without stopping preprocessing.
+    fn report_error(&self, line_number: usize, message: &str) {
+        self.report_directive_error(line_number, message, Vec::new());
+    }
+
+    /// Handles `#line` and gcc style `# 123 "file" flags` linemarkers, which
+    /// set the presumed line number, and optionally the presumed file name, of
+    /// the line that follows them.
+    fn change_line(&mut self, operand: &str, line_number: usize, linemarker: bool) -> Result<(), PreprocessError> {
+        let operand = if linemarker || LINE_OPERAND_RE.is_match(operand) {
+            operand.to_string()
+        }
+        else {
+            self.expand_text(operand, line_number)?
+        };
+
+        let caps = match LINE_OPERAND_RE.captures(&operand) {
+            Some(caps) => caps,
+            None => {
+                self.report_error(line_number, &format!("{:?} after #line is not a line number and optional file name", operand));
+                return Ok(());
+            }
+        };
+
+        if !linemarker && !caps.get(3).unwrap().as_str().is_empty() {
+            self.report_error(line_number, "extra tokens at end of #line directive");
+            return Ok(());
+        }
+
+        let new_line = match caps.get(1).unwrap().as_str().parse::<usize>() {
+            Ok(new_line) => new_line,
+            Err(_) => {
+                self.report_error(line_number, "line number out of range in #line directive");
+                return Ok(());
+            }
+        };
+
+        if let Some(file) = self.include_stack.last().map(|frame| frame.file) {
+            let name = caps.get(2).map(|name| name.as_str().to_string());
+            self.sources.add_line_directive(file, line_number, new_line, name);
+        }
+        self.write_linemarker(line_number + 1, None);
+
+        // flag 3 on a linemarker says that the lines after it are from a
+        // system header
+        if linemarker && caps.get(3).unwrap().as_str().split_whitespace().any(|flag| flag == "3") {
+            if let Some(frame) = self.include_stack.last_mut() {
+                frame.system_header = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The state of the innermost open conditional, or `WithinTrueBlock` when
+    /// there isn't one.
+    fn current_state(&self) -> State {
+        self.state_stack.last().map_or(State::WithinTrueBlock, |cond| cond.state)
+    }
+
+    fn push_conditional(&mut self, state: State, line_number: usize) {
+        self.state_stack.push(Conditional { state: state, opened_line: line_number, else_line: None });
+    }
+
+    /// The number of conditionals that the current file can close.
+    fn file_conditional_depth(&self) -> usize {
+        let base_depth = self.include_stack.last().map_or(0, |frame| frame.conditional_depth);
+        self.state_stack.len() - base_depth
+    }
+
+    /// Checks that an `#elif`, `#else` or `#endif` has a matching `#if` in the
+    /// current file, and for everything but `#endif` that it does not come
+    /// after the conditional's `#else`.
+    fn check_conditional_structure(&self, directive: &str, line_number: usize) -> Result<(), PreprocessError> {
+        if self.file_conditional_depth() == 0 {
+            return Err(self.syntax_error(line_number, format!("#{} without #if", directive)));
+        }
+
+        let cond = self.state_stack.last().unwrap();
+
+        if let Some(else_line) = cond.else_line {
+            if directive != "endif" {
+                let message = format!("#{} after #else", directive);
+                let notes = vec![
+                    self.directive_note(else_line, "the #else is here"),
+                    self.directive_note(cond.opened_line, "the conditional started here"),
+                ];
+                let location = self.report_directive_error(line_number, &message, notes);
+                return Err(PreprocessError::DirectiveSyntax { location: location, message: message });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn directive_note(&self, line_number: usize, message: &str) -> Diagnostic {
+        let (location, length) = self.directive_location(line_number);
+        Diagnostic::new(Severity::Note, message.to_string()).at(location, length)
+    }
+
+    /// Moves the innermost conditional on to the group of an `#elif`,
+    /// `#elifdef` or `#elifndef`.  The condition is only evaluated when no
+    /// earlier group of the conditional has been taken.
+    fn enter_elif<F>(&mut self, directive: &str, line_number: usize, condition: F) -> Result<(), PreprocessError>
+        where F: FnOnce(&mut Self) -> Result<bool, PreprocessError>
+    {
+        self.check_conditional_structure(directive, line_number)?;
+
+        let current_state = self.current_state();
+
+        if current_state == State::NotYetFound && condition(self)? {
+            self.state_stack.last_mut().unwrap().state = State::WithinTrueBlock;
+        }
+        else if current_state == State::WithinTrueBlock {
+            self.state_stack.last_mut().unwrap().state = State::AlreadyFound;
+        }
+
+        Ok(())
+    }
+
+    /// Reports a -Wpragmas warning about the pragma on `line_number`.
+    fn pragma_warning(&self, line_number: usize, message: String) {
+        let (location, length) = self.directive_location(line_number);
+        self.emit(Diagnostic::warning(Warning::Pragmas, message).at(location, length));
+    }
+
+    /// Writes the pragma on `line_number` to the output for the compiler to
+    /// handle.
+    fn pass_through_pragma(&mut self, operand: &str, line_number: usize) {
+        self.write_directive_output(&format!("#pragma {}\n", operand), line_number);
+    }
+
+    /// Dispatches a pragma on its namespace.  Pragmas that are meant for the
+    /// compiler, including every pragma that is not known here, are passed
+    /// through to the output.
+    fn run_pragma(&mut self, operand: &str, line_number: usize) -> Result<(), PreprocessError> {
+        let (namespace, rest) = split_pragma_name(operand);
+
+        match namespace {
+            "once" => {
+                self.pragma_once(line_number);
+            }
+            "GCC" | "clang" => {
+                let (name, rest) = split_pragma_name(rest);
+                match name {
+                    "diagnostic" => {
+                        let mut words = rest.split_whitespace();
+                        let kind = words.next().unwrap_or("");
+                        let option = words.next().unwrap_or("");
+                        self.diagnostic_pragma(namespace, kind, option, line_number);
+                    }
+                    "poison" => {
+                        self.poison_identifiers(rest, line_number);
+                    }
+                    "warning" | "error" => {
+                        self.message_pragma(name, rest, line_number)?;
+                        return Ok(());
+                    }
+                    "dependency" => {
+                        self.dependency_pragma(rest, line_number)?;
+                        return Ok(());
+                    }
+                    "system_header" => {
+                        // the linemarker that this writes takes the place of
+                        // the pragma in the output
+                        self.system_header_pragma(line_number);
+                        return Ok(());
+                    }
+                    "deprecated" | "restrict_expansion" if namespace == "clang" => {
+                        self.annotate_macro(name, rest, line_number);
+                    }
+                    _ => {}
+                }
+                self.pass_through_pragma(operand, line_number);
+            }
+            "STDC" => {
+                self.stdc_pragma(rest, line_number);
+                self.pass_through_pragma(operand, line_number);
+            }
+            "message" => {
+                // the compiler reports the message as well
+                self.message_pragma(namespace, rest, line_number)?;
+                self.pass_through_pragma(operand, line_number);
+            }
+            "push_macro" | "pop_macro" => {
+                self.push_or_pop_macro(namespace, rest, line_number);
+                self.pass_through_pragma(operand, line_number);
+            }
+            _ if EXPANDED_PRAGMAS.contains(&namespace) => {
+                let expanded = self.expand_text(rest, line_number)?;
+                self.pass_through_pragma(&format!("{} {}", namespace, expanded), line_number);
+            }
+            _ => {
+                self.pass_through_pragma(operand, line_number);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles `#pragma push_macro("NAME")` and `#pragma pop_macro("NAME")`.
+    fn push_or_pop_macro(&mut self, pragma: &str, operand: &str, line_number: usize) {
+        let name = match parenthesized_operand(operand, 0).and_then(|(literal, _)| destringize(literal)) {
+            Some(name) => name,
+            None => {
+                self.pragma_warning(line_number, format!("#pragma {} requires a parenthesized string literal", pragma));
+                return;
+            }
+        };
+
+        if pragma == "push_macro" {
+            self.macros.push_macro(&name);
+        }
+        else if !self.macros.pop_macro(&name) {
+            self.pragma_warning(line_number, format!("#pragma pop_macro(\"{}\") without a matching push_macro", name));
+        }
+    }
+
+    /// Handles `#pragma GCC poison`, which makes any later use of the
+    /// identifiers that follow it an error.
+    fn poison_identifiers(&mut self, operand: &str, line_number: usize) {
+        for name in operand.split_whitespace() {
+            if !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                self.report_error(line_number, &format!("invalid #pragma GCC poison directive, {:?} is not an identifier", name));
+                return;
+            }
+
+            if self.macros.is_defined(name) {
+                self.pragma_warning(line_number, format!("poisoning existing macro \"{}\"", name));
+            }
+            if !self.poisoned.contains_key(name) {
+                let users = self.macros.users_of(name);
+                self.poisoned.insert(name.to_string(), users);
+            }
+        }
+    }
+
+    /// Handles `#pragma message`, `#pragma GCC warning` and `#pragma GCC
+    /// error`, which report the string literal after them, optionally in
+    /// parentheses, as a note, a warning or an error.
+    fn message_pragma(&mut self, pragma: &str, operand: &str, line_number: usize) -> Result<(), PreprocessError> {
+        // only #pragma message expands macros in its operand
+        let text = if pragma == "message" { self.expand_text(operand, line_number)? } else { operand.trim().to_string() };
+        let literal = match parenthesized_operand(&text, 0) {
+            Some((inner, end)) if text[end..].trim().is_empty() => inner,
+            _ => text.as_str(),
+        };
+
+        let message = match destringize(literal) {
+            Some(message) => message,
+            None => {
+                let spelling = if pragma == "message" { String::from("message") } else { format!("GCC {}", pragma) };
+                self.pragma_warning(line_number, format!("expected a string literal after '#pragma {}'", spelling));
+                return Ok(());
+            }
+        };
+
+        let diagnostic = match pragma {
+            "message" => Diagnostic::new(Severity::Note, format!("#pragma message: {}", message)),
+            "warning" => Diagnostic::warning(Warning::Cpp, message),
+            _ => Diagnostic::new(Severity::Error, message),
+        };
+        let (location, length) = self.directive_location(line_number);
+        self.emit(diagnostic.at(location, length));
+        Ok(())
+    }
+
+    /// Handles `#pragma GCC dependency`, which warns when the file it names is
+    /// newer than the current one.  Any text after the file name is added to
+    /// the warning.
+    fn dependency_pragma(&mut self, operand: &str, line_number: usize) -> Result<(), PreprocessError> {
+        let (header, extra) = match self.split_embed_operand(operand, line_number)? {
+            Some(split) => split,
+            None => {
+                let message = format!("expected \"FILENAME\" or <FILENAME> after '#pragma GCC dependency' but found {:?}", operand.trim());
+                self.pragma_warning(line_number, message);
+                return Ok(());
+            }
+        };
+
+        let (path, _) = match self.find_header(&header, false) {
+            Some(found) => found,
+            None => return Err(self.header_not_found(&header, false, line_number)),
+        };
+
+        let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        let current = self.include_stack.last().and_then(|frame| modified(&frame.path));
+
+        if let (Some(dependency), Some(current)) = (modified(&path), current) {
+            if dependency > current {
+                let (location, length) = self.directive_location(line_number);
+                let mut diagnostic = Diagnostic::warning(Warning::Cpp, format!("current file is older than {}", header)).at(location, length);
+                if !extra.trim().is_empty() {
+                    diagnostic.notes.push(Diagnostic::new(Severity::Note, extra.trim().to_string()));
+                }
+                self.emit(diagnostic);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles `#pragma GCC system_header`, which makes the rest of the
+    /// current file a system header.
+    fn system_header_pragma(&mut self, line_number: usize) {
+        if self.include_stack.len() <= 1 {
+            self.pragma_warning(line_number, String::from("#pragma system_header ignored outside include file"));
+            return;
+        }
+
+        if let Some(frame) = self.include_stack.last_mut() {
+            frame.system_header = true;
+        }
+        self.write_linemarker(line_number + 1, None);
+    }
+
+    /// Handles `#pragma clang deprecated(NAME)` and `#pragma clang
+    /// restrict_expansion(NAME)`, which take an optional string literal
+    /// message after the macro name.
+    fn annotate_macro(&mut self, pragma: &str, operand: &str, line_number: usize) {
+        let annotation = parenthesized_operand(operand, 0).and_then(|(inner, _)| {
+            let mut parts = inner.splitn(2, ',');
+            let name = parts.next().unwrap_or("").trim().to_string();
+            match parts.next() {
+                Some(literal) => Some((name, Some(destringize(literal)?))),
+                None => Some((name, None)),
+            }
+        });
+
+        let (name, message) = match annotation {
+            Some(annotation) => annotation,
+            None => {
+                self.pragma_warning(line_number, format!("expected (NAME) or (NAME, \"message\") after '#pragma clang {}'", pragma));
+                return;
+            }
+        };
+
+        if !self.macros.is_defined(&name) {
+            self.pragma_warning(line_number, format!("no macro named '{}'", name));
+            return;
+        }
+
+        if pragma == "deprecated" {
+            self.deprecated_macros.insert(name, message);
+        }
+        else {
+            self.restricted_macros.insert(name, message);
         }
     }
 
-    fn get_header_contents(&self, file_name: &Path) -> io::Result<String> {
-        for include_dir in self.config.include_dirs.iter() {
-            let full_path = include_dir.join(file_name);
-            if full_path.is_file() {
-                let mut file = File::open(full_path)?;
-                let mut contents = String::new();
-                file.read_to_string(&mut contents);
-                return Ok(contents);
-            }
+    fn pragma_once(&mut self, line_number: usize) {
+        if self.include_stack.len() <= 1 {
+            self.pragma_warning(line_number, String::from("#pragma once in main file"));
         }
 
-        Err(io::Error::new(io::ErrorKind::NotFound, "Header file not found"))
+        if let Some(path) = self.include_stack.last().map(|frame| canonical_path(&frame.path)) {
+            self.once_only.insert(path);
+        }
     }
 
-    fn include_source(&mut self, s: &str) {
-        let re = Regex::new(r###"\s*("([^"]+)"|<([^<>]+)>)\s*"###).unwrap();
-        let path: &Path;
+    /// Checks the standard `FP_CONTRACT`, `FENV_ACCESS` and `CX_LIMITED_RANGE`
+    /// pragmas, which the compiler carries out.
+    fn stdc_pragma(&self, operand: &str, line_number: usize) {
+        let mut words = operand.split_whitespace();
 
-        if let Some(caps) = re.captures(s) {
-            if let Some(header_name) = caps.get(2) {
-                path = Path::new(header_name.as_str());
-            }
-            else if let Some(header_name) = caps.get(3) {
-                path = Path::new(header_name.as_str());
+        match (words.next(), words.next(), words.next()) {
+            (Some("FP_CONTRACT"), Some(switch), None) |
+            (Some("FENV_ACCESS"), Some(switch), None) |
+            (Some("CX_LIMITED_RANGE"), Some(switch), None) => {
+                if !["ON", "OFF", "DEFAULT"].contains(&switch) {
+                    self.pragma_warning(line_number, format!("expected ON, OFF or DEFAULT in #pragma STDC but found {:?}", switch));
+                }
             }
-            else {
-                panic!("Something bad happened, idk what");
+            (Some("FENV_ROUND"), Some(_), None) | (Some("FENV_DEC_ROUND"), Some(_), None) => {}
+            _ => {
+                self.pragma_warning(line_number, format!("unknown #pragma STDC {}", operand));
             }
         }
-        else {
-            panic!("Ill formatted include directive");
-        }
+    }
+
+    /// Handles `#pragma GCC diagnostic`, which changes the warning options
+    /// from the pragma onwards.
+    fn diagnostic_pragma(&mut self, namespace: &str, kind: &str, option: &str, line_number: usize) {
+        let location = self.location(line_number, 1);
+
+        let level = match kind {
+            "push" => {
+                self.diagnostics.push_warnings();
+                return;
+            }
+            "pop" => {
+                if !self.diagnostics.pop_warnings(location) {
+                    self.pragma_warning(line_number, format!("#pragma {} diagnostic pop without a matching push", namespace));
+                }
+                return;
+            }
+            "ignored" => Level::Ignored,
+            "warning" => Level::Warning,
+            "error" => Level::Error,
+            _ => {
+                let message = format!("expected [error|warning|ignored|push|pop] after '#pragma {} diagnostic'", namespace);
+                self.pragma_warning(line_number, message);
+                return;
+            }
+        };
 
-        let source = self.get_header_contents(path).unwrap();
+        let name = match destringize(option) {
+            Some(ref name) if name.starts_with("-W") => name[2..].to_string(),
+            _ => {
+                let message = format!("unknown option {} after '#pragma {} diagnostic' kind", option, namespace);
+                self.pragma_warning(line_number, message);
+                return;
+            }
+        };
 
-        self.preprocess_source(&source);
+        // the pragma is passed through, so the compiler takes care of the
+        // warnings that are not the preprocessor's
+        if let Some(warning) = Warning::from_name(&name) {
+            self.diagnostics.override_warning(warning, level, location);
+        }
     }
 
-    fn run_directive(&mut self, line: &str) {
-        let re = Regex::new(r"^\s*#\s*([a-z]+)\s*(.*)\s*$").unwrap();
+    fn run_directive(&mut self, line: &str, line_number: usize) -> Result<(), PreprocessError> {
+        let line = strip_directive_comments(line);
+        let active = self.current_state() == State::WithinTrueBlock;
+
+        let caps = match DIRECTIVE_RE.captures(&line) {
+            Some(caps) => caps,
+            None => {
+                if active {
+                    self.report_error(line_number, "ill formatted preprocessing directive");
+                }
+                return Ok(());
+            }
+        };
+        let operand = caps.get(3).map_or("", |arg| arg.as_str());
+
+        let directive = match (caps.get(1), caps.get(2)) {
+            (Some(name), _) => name.as_str(),
+            (None, Some(number)) => {
+                if active {
+                    let operand = format!("{} {}", number.as_str(), operand);
+                    self.change_line(&operand, line_number, true)?;
+                }
+                return Ok(());
+            }
+            (None, None) => {
+                // a '#' on its own is the null directive, which does nothing
+                if active && !operand.is_empty() {
+                    self.report_error(line_number, &format!("invalid preprocessing directive, expected a directive name but found {:?}", operand));
+                }
+                return Ok(());
+            }
+        };
 
-        let caps = re.captures(line).expect("Ill formatted preprocessor directive");
+        // skipped groups are only scanned for the directives that open,
+        // continue or close a conditional, everything else is ignored
+        if !active && !CONDITIONAL_DIRECTIVES.contains(&directive) {
+            return Ok(());
+        }
 
-        match caps.get(1).unwrap().as_str() {
+        match directive {
             "include" => {
-                if self.state_stack.last().cloned().unwrap_or(State::WithinTrueBlock) == State::WithinTrueBlock {
-                    self.include_source(caps.get(2).unwrap().as_str());
+                self.include_source(operand, false, false, line_number)?;
+            }
+            "include_next" => {
+                self.include_source(operand, true, false, line_number)?;
+            }
+            "import" => {
+                if self.config.language != Language::ObjectiveC {
+                    self.report_error(line_number, "#import is only available in Objective-C, select it with -x objective-c");
+                    return Ok(());
                 }
+                self.include_source(operand, false, true, line_number)?;
+            }
+            "embed" => {
+                self.embed_resource(operand, line_number)?;
             }
             "define" => {
-                if self.state_stack.last().cloned().unwrap_or(State::WithinTrueBlock) == State::WithinTrueBlock {
-                    self.macros.define(caps.get(2).unwrap().as_str());
+                let name = operand.split(|c: char| !(c.is_alphanumeric() || c == '_')).next().unwrap_or("");
+                if self.poisoned.contains_key(name) {
+                    self.poisoned_use(name, self.token_location(line_number, name));
+                    return Ok(());
+                }
+                // only the definitions from before the poisoning may use a
+                // poisoned identifier
+                for (begin, end) in tokenizer::iter_tokens(operand.to_string()).skip(1) {
+                    if self.poisoned.contains_key(&operand[begin..end]) {
+                        self.poisoned_use(&operand[begin..end], self.token_location(line_number, &operand[begin..end]));
+                    }
+                }
+                for users in self.poisoned.values_mut() {
+                    users.remove(name);
+                }
+                let (location, length) = self.token_location(line_number, name);
+                let previous = self.macros.get(name).cloned();
+                let previous_location = self.macros.location(name);
+
+                self.macros.define_at(operand, location).map_err(|message| self.macro_error(line_number, message))?;
+                self.used_macros.remove(name);
+
+                if previous.is_some() && self.macros.get(name) != previous.as_ref() {
+                    let diagnostic = Diagnostic::warning(Warning::MacroRedefined, format!("\"{}\" redefined", name))
+                        .at(location, length)
+                        .with_note(previous_location, String::from("this is the location of the previous definition"));
+                    self.emit(diagnostic);
                 }
             }
             "undef" => {
-                if self.state_stack.last().cloned().unwrap_or(State::WithinTrueBlock) == State::WithinTrueBlock {
-                    self.macros.undef(caps.get(2).unwrap().as_str());
+                if self.poisoned.contains_key(operand) {
+                    self.poisoned_use(operand, self.token_location(line_number, operand));
+                    return Ok(());
+                }
+                for users in self.poisoned.values_mut() {
+                    users.remove(operand);
                 }
+                if let Some(main_file) = self.include_stack.first().map(|frame| frame.file) {
+                    self.check_unused_macro(operand, main_file);
+                }
+                self.macros.undef(operand);
             }
             "error" => {
-                if self.state_stack.last().cloned().unwrap_or(State::WithinTrueBlock) == State::WithinTrueBlock {
-                    panic!("Error: {}", caps.get(2).map(|arg| arg.as_str()).unwrap_or("**No Error Message**"));
-                }
+                let location = self.report_directive_error(line_number, &format!("#error {}", operand), Vec::new());
+                return Err(PreprocessError::ErrorDirective { location: location, message: operand.to_string() });
             }
             "warning" => {
-                eprintln!("Warning: {}", caps.get(2).map(|arg| arg.as_str()).unwrap_or("**No Warning Message**"));
+                let (location, length) = self.directive_location(line_number);
+                self.emit(Diagnostic::warning(Warning::Cpp, format!("#warning {}", operand)).at(location, length));
             }
             "ifdef" => {
-                let parent_state = self.state_stack.last().cloned().unwrap_or(State::WithinTrueBlock);
+                let parent_state = self.current_state();
 
                 if parent_state == State::WithinTrueBlock {
-                    if self.macros.is_defined(caps.get(2).unwrap().as_str()) {
-                        self.state_stack.push(State::WithinTrueBlock);
+                    if self.is_defined(operand, line_number) {
+                        self.push_conditional(State::WithinTrueBlock, line_number);
                     }
                     else {
-                        self.state_stack.push(State::NotYetFound);
+                        self.push_conditional(State::NotYetFound, line_number);
                     }
                 }
                 else {
-                    self.state_stack.push(State::AlreadyFound);
+                    self.push_conditional(State::AlreadyFound, line_number);
                 }
             }
             "ifndef" => {
-                let parent_state = self.state_stack.last().cloned().unwrap_or(State::WithinTrueBlock);
+                let parent_state = self.current_state();
 
                 if parent_state == State::WithinTrueBlock {
-                    if !self.macros.is_defined(caps.get(2).unwrap().as_str()) {
-                        self.state_stack.push(State::WithinTrueBlock);
+                    if !self.is_defined(operand, line_number) {
+                        self.push_conditional(State::WithinTrueBlock, line_number);
                     }
                     else {
-                        self.state_stack.push(State::NotYetFound);
+                        self.push_conditional(State::NotYetFound, line_number);
                     }
                 }
                 else {
-                    self.state_stack.push(State::AlreadyFound);
+                    self.push_conditional(State::AlreadyFound, line_number);
                 }
             }
             "if" => {
-                let parent_state = self.state_stack.last().cloned().unwrap_or(State::WithinTrueBlock);
+                let parent_state = self.current_state();
 
                 if parent_state == State::WithinTrueBlock {
-                    if self.macros.expand_condition(caps.get(2).unwrap().as_str()) {
-                        self.state_stack.push(State::WithinTrueBlock);
+                    // an invalid condition has been reported, and counts as false
+                    let condition = self.evaluate_condition(operand, line_number);
+                    if self.recover(condition, false)? {
+                        self.push_conditional(State::WithinTrueBlock, line_number);
                     }
                     else {
-                        self.state_stack.push(State::NotYetFound);
+                        self.push_conditional(State::NotYetFound, line_number);
                     }
                 }
                 else {
-                    self.state_stack.push(State::AlreadyFound);
+                    self.push_conditional(State::AlreadyFound, line_number);
                 }
             }
             "elif" => {
-                let current_state = self.state_stack.last().cloned().expect("Ill formatted conditional directive");
-
-                if current_state == State::NotYetFound && self.macros.expand_condition(caps.get(2).unwrap().as_str()) {
-                    *self.state_stack.last_mut().unwrap() = State::WithinTrueBlock;
-                }
-                else if current_state == State::WithinTrueBlock {
-                    *self.state_stack.last_mut().unwrap() = State::AlreadyFound;
+                let condition = operand;
+                self.enter_elif("elif", line_number, |cpp| {
+                    let value = cpp.evaluate_condition(condition, line_number);
+                    cpp.recover(value, false)
+                })?;
+            }
+            directive @ "elifdef" | directive @ "elifndef" => {
+                if self.config.std < Standard::C23 {
+                    // before C23 these are unknown directives, which a skipped
+                    // group ignores
+                    if !active {
+                        return Ok(());
+                    }
+                    // the directive is still carried out, so that the rest of
+                    // the conditional is not thrown off
+                    let message = format!("#{} is only available in C23 and later, but the preprocessor is in {} mode",
+                                          directive, self.config.std_name());
+                    self.report_error(line_number, &message);
                 }
+                let macro_name = operand;
+                let expect_defined = directive == "elifdef";
+                self.enter_elif(directive, line_number, |cpp| Ok(cpp.is_defined(macro_name, line_number) == expect_defined))?;
             }
             "else" => {
-                let current_state = self.state_stack.last().cloned().expect("Ill formatted conditional directive");
+                self.check_conditional_structure("else", line_number)?;
 
-                if current_state == State::WithinTrueBlock {
-                    *self.state_stack.last_mut().unwrap() = State::AlreadyFound;
+                let cond = self.state_stack.last_mut().unwrap();
+
+                if cond.state == State::WithinTrueBlock {
+                    cond.state = State::AlreadyFound;
                 }
-                else if current_state == State::NotYetFound {
-                    *self.state_stack.last_mut().unwrap() = State::WithinTrueBlock;
+                else if cond.state == State::NotYetFound {
+                    cond.state = State::WithinTrueBlock;
                 }
+                cond.else_line = Some(line_number);
             }
             "endif" => {
-                self.state_stack.pop().expect("Ill formatted conditional directives");
+                self.check_conditional_structure("endif", line_number)?;
+                self.state_stack.pop();
+            }
+            "line" => {
+                self.change_line(operand, line_number, false)?;
+            }
+            "pragma" => {
+                self.run_pragma(operand, line_number)?;
+            }
+            directive @ "ident" | directive @ "sccs" => {
+                if destringize(operand).is_none() {
+                    self.report_error(line_number, &format!("invalid #{} directive, expected a string literal but found {:?}", directive, operand));
+                    return Ok(());
+                }
+                self.write_directive_output(&format!("#ident {}\n", operand.trim()), line_number);
+            }
+            directive @ "assert" | directive @ "unassert" => {
+                if self.check_gnu_directive(directive, line_number) {
+                    self.change_assertion(directive, operand, line_number);
+                }
             }
             other => {
-                panic!("Unrecognized preprocessor directive {:?}", other);
+                let mut notes = Vec::new();
+                if let Some(suggestion) = closest_match(other, DIRECTIVES.iter().map(|name| name.to_string())) {
+                    notes.push(self.directive_note(line_number, &format!("did you mean #{}?", suggestion)));
+                }
+                self.report_directive_error(line_number, &format!("invalid preprocessing directive #{}", other), notes);
             }
         }
+
+        Ok(())
     }
 
 
-    pub fn feed_line(&mut self, s: &str, line_number: usize) {
-        self.input.push_str(s);
-        self.process_input(line_number);
+    pub fn feed_line(&mut self, s: &str, line_number: usize) -> Result<(), PreprocessError> {
+        self.feed_text(s, line_number, Some(1))
+    }
+
+    /// Adds `text` to the pending input and processes it.  The text starts at
+    /// `column` of the line, when it is known where on the line it is.
+    fn feed_text(&mut self, text: &str, line_number: usize, column: Option<usize>) -> Result<(), PreprocessError> {
+        if self.input.is_empty() {
+            self.sync_output(line_number);
+        }
+        for (offset, c) in text.chars().enumerate() {
+            let location = column.and_then(|column| self.location(line_number, column + offset));
+            self.input_locations.extend(std::iter::repeat(location).take(c.len_utf8()));
+        }
+        self.input.push_str(text);
+        self.process_input(line_number)
     }
 
     pub fn get_output(&self) -> String {
@@ -247,8 +2043,6 @@ impl<'b> PreProcessor<'b> {
         let mut arg_end = arg_begin;
         let mut end_of_args = s.len();
 
-        eprintln!("gather_macro_args recieved: {:?}", s);
-
         for (begin, end) in tokenizer::iter_tokens(String::from(s)) {
             let token = &s[begin..end];
 
@@ -279,8 +2073,6 @@ impl<'b> PreProcessor<'b> {
             }
         }
 
-        eprintln!("gather_macro_args returning: {:?}", args);
-
         if pdepth > 0 {
             None
         }
@@ -289,37 +2081,73 @@ impl<'b> PreProcessor<'b> {
         }
     }
 
-    pub fn process_input(&mut self, line_number: usize) {
+    pub fn process_input(&mut self, line_number: usize) -> Result<(), PreprocessError> {
         let mut buf = String::new();
         let mut cursor = 0usize;
         let mut iter = tokenizer::iter_tokens(self.input.clone());
+        let mut expansions = ExpansionStack::default();
 
         while let Some((begin, end)) = iter.next() {
             let token = String::from(&self.input[begin..end]);
+            if let Some(users) = self.poisoned.get(&token) {
+                // a macro that was using the identifier before it was poisoned
+                // can still expand to it
+                let stack = expansions.at(begin);
+                if !stack.last().map_or(false, |&name| users.contains(name)) {
+                    self.poisoned_use(&token, self.input_token_location(line_number, begin, end, &stack));
+                    buf.push_str(&self.input[cursor..end]);
+                    cursor = end;
+                    continue;
+                }
+            }
+
             if let Some(macro_val) = self.macros.get(&token) {
                 let next_c = self.input.get(end..).and_then(|s| s.chars().next());
                 match macro_val {
                     MacroValue::Constant(ref text) => {
-                        self.input.replace_range(begin..end, text);
+                        let location = self.input_token_location(line_number, begin, end, &expansions.at(begin));
+                        self.check_macro_annotations(&token, location, &expansions.at(begin));
+                        expansions.expand(&token, begin, end, text.len());
+                        self.used_macros.insert(token.clone());
+                        replace_input(&mut self.input, &mut self.input_locations, begin, end, text);
                         iter = tokenizer::iter_tokens(self.input.clone());
                         iter.set_cursor(cursor);
                     }
                     MacroValue::Function(ref text, ref params) => {
                         if next_c == Some('(') {
                             if let Some((offset, args)) = self.gather_macro_args(&self.input[end..].to_string()) {
-                                let expanded = expand_function_macro(text, params, args);
-                                eprintln!("expanded text is: {:?}", expanded);
-                                self.input.replace_range(begin..(end+offset), &expanded);
+                                let expanded = match expand_function_macro(&token, text, params, args) {
+                                    Ok(expanded) => expanded,
+                                    Err(message) => {
+                                        let location = self.input_token_location(line_number, begin, end, &expansions.at(begin));
+                                        let diagnostic = self.expansion_diagnostic(Severity::Error, message, location, &expansions.at(begin))
+                                            .with_note(self.macros.location(&token), format!("macro '{}' defined here", token));
+                                        self.emit(diagnostic);
+
+                                        // leave the invocation as it is and carry on after it
+                                        buf.push_str(&self.input[cursor..end + offset]);
+                                        cursor = end + offset;
+                                        iter.set_cursor(cursor);
+                                        continue;
+                                    }
+                                };
+                                let location = self.input_token_location(line_number, begin, end, &expansions.at(begin));
+                                self.check_macro_annotations(&token, location, &expansions.at(begin));
+                                expansions.expand(&token, begin, end + offset, expanded.len());
+                                self.used_macros.insert(token.clone());
+                                replace_input(&mut self.input, &mut self.input_locations, begin, end + offset, &expanded);
                                 iter = tokenizer::iter_tokens(self.input.clone());
                                 iter.set_cursor(cursor);
                             }
                             else {
                                 self.input.drain(..cursor);
+                                self.input_locations.drain(..cursor);
                                 self.output.push_str(&buf);
-                                return;
+                                return Ok(());
                             }
                         }
                         else {
+                            self.collect_condition_identifier(line_number, begin, end, &expansions.at(begin));
                             buf.push_str(&self.input[cursor..end]);
                             cursor = end;
                         }
@@ -338,7 +2166,8 @@ impl<'b> PreProcessor<'b> {
                         s.push_str(&self.input[next_begin..next_end]);
                     }
                     s.push('"');
-                    self.input.replace_range(begin..next_end, &s);
+                    expansions.replaced(begin, next_end, s.len());
+                    replace_input(&mut self.input, &mut self.input_locations, begin, next_end, &s);
                     iter = tokenizer::iter_tokens(self.input.clone());
                     iter.set_cursor(cursor);
                 }
@@ -361,14 +2190,54 @@ impl<'b> PreProcessor<'b> {
                     cursor = next_end;
                 }
             }
+            else if token == "_Pragma" {
+                let pragma = parenthesized_operand(&self.input, end)
+                    .and_then(|(operand, operand_end)| Some((destringize(operand)?, operand_end)));
+
+                match pragma {
+                    Some((pragma, operand_end)) => {
+                        // a pragma that is passed through has to go on a line
+                        // of its own, between the text before and after it
+                        buf.push_str(&self.input[cursor..begin]);
+                        if !buf.trim().is_empty() {
+                            self.output.push_str(&buf);
+                            self.output.push('\n');
+                        }
+                        buf.clear();
+                        cursor = operand_end;
+                        iter.set_cursor(cursor);
+
+                        // the text before the operator has been written, so the
+                        // pragma's output goes straight after it rather than
+                        // waiting for the rest of the line
+                        let pending = std::mem::replace(&mut self.input, String::new());
+                        let result = self.run_pragma(&pragma, line_number);
+                        self.input = pending;
+                        // the rest of the line is no longer where the source has it
+                        self.sync_output(line_number);
+
+                        // the error has been reported, so the rest of the line
+                        // carries on unless preprocessing has to stop
+                        if let Err(err) = self.recover(result, ()) {
+                            self.finish_input();
+                            return Err(err);
+                        }
+                    }
+                    None => {
+                        let (location, length) = self.input_token_location(line_number, begin, end, &expansions.at(begin));
+                        let message = String::from("_Pragma takes a parenthesized string literal");
+                        self.emit(Diagnostic::new(Severity::Error, message).at(location, length));
+                        buf.push_str(&self.input[cursor..end]);
+                        cursor = end;
+                    }
+                }
+            }
             else if token == "__LINE__" {
-                eprintln!("__LINE__ token encountered");
                 buf.push_str(&self.input[cursor..begin]);
-                buf.push_str(&line_number.to_string());
+                buf.push_str(&self.presumed_line(line_number).to_string());
                 cursor = end;
             }
             else if token.starts_with("\"") && DOUBLE_QUOTE_RE.is_match(&buf) {
-                eprintln!("Encountered two string literals in a row, the second one is: {:?}", token);
                 while let Some(c) = buf.pop() {
                     if c == '"' {
                         break;
@@ -380,6 +2249,7 @@ impl<'b> PreProcessor<'b> {
                 cursor = end;
             }
             else {
+                self.collect_condition_identifier(line_number, begin, end, &expansions.at(begin));
                 buf.push_str(&self.input[cursor..end]);
                 cursor = end;
             }
@@ -387,21 +2257,807 @@ impl<'b> PreProcessor<'b> {
 
         self.output.push_str(&buf);
         self.output.push('\n');
+        self.finish_input();
+        Ok(())
+    }
+
+    /// Discards the pending input once it has been dealt with, and writes the
+    /// output that was deferred until then.
+    fn finish_input(&mut self) {
         self.input.clear();
+        self.input_locations.clear();
+
+        let deferred = std::mem::replace(&mut self.deferred_output, String::new());
+        self.output.push_str(&deferred);
+    }
+
+    /// Reads and preprocesses the file at `path`.  Fails when the file cannot
+    /// be read, and otherwise as `preprocess_file` does.
+    pub fn preprocess_path(&mut self, path: &Path) -> Result<(), PreprocessError> {
+        let source = read_file(path).map_err(|err| {
+            self.emit(Diagnostic::new(Severity::Fatal, format!("{}: {}", path.display(), err)));
+            PreprocessError::Io { path: path.to_path_buf(), error: err }
+        })?;
+
+        self.preprocess_file(path, &source)
+    }
+
+    /// Preprocesses `source` as the contents of the file at `path`, keeping
+    /// track of it on the include stack for the duration.
+    ///
+    /// Errors such as a missing header, a bad `#if` or an `#error` are
+    /// reported and recovered from, so this returns `Ok` even when there were
+    /// some, and only fails once `-fmax-errors` is reached.  Check
+    /// `error_count` afterwards to tell whether preprocessing succeeded, and
+    /// `errors` for what went wrong.
+    pub fn preprocess_file(&mut self, path: &Path, source: &str) -> Result<(), PreprocessError> {
+        let file = self.sources.add_file(path, source);
+        let frame = IncludeFrame {
+            path: path.to_path_buf(),
+            file: file,
+            included_at: None,
+            search_index: None,
+            conditional_depth: 0,
+            system_header: false,
+        };
+        self.preprocess_frame(frame, source)?;
+
+        if self.include_stack.is_empty() {
+            self.check_unused_macros(file);
+        }
+        Ok(())
+    }
+
+    fn preprocess_frame(&mut self, mut frame: IncludeFrame, source: &str) -> Result<(), PreprocessError> {
+        frame.conditional_depth = self.state_stack.len();
+        self.entered_files.insert(canonical_path(&frame.path));
+        self.include_stack.push(frame);
+        // the warning options that are in effect here carry on into the file,
+        // and any changes the file makes carry on back out of it
+        let start = self.location(0, 1);
+        self.diagnostics.record_position(start);
+        let entered = if self.include_stack.len() > 1 { Some("1") } else { None };
+        self.write_linemarker(1, entered);
+
+        let result = self.preprocess_source(source);
+
+        let frame = self.include_stack.pop().unwrap();
+        self.diagnostics.record_position(frame.included_at);
+        if let Some(included_at) = frame.included_at {
+            self.write_linemarker(included_at.line + 1, Some("2"));
+        }
+        result
+    }
+
+    /// Writes a linemarker saying that the next line of output comes from
+    /// `line_number` of the current file, with the flag for entering or
+    /// returning to the file if there is one, and flag 3 in a system header.
+    fn write_linemarker(&mut self, line_number: usize, flag: Option<&str>) {
+        if !self.config.line_markers {
+            return;
+        }
+
+        let (location, system_header) = match self.include_stack.last() {
+            Some(frame) => (SourceLocation { file: frame.file, line: line_number, column: 1 }, frame.system_header),
+            None => return,
+        };
+        let (name, line) = self.sources.presumed(location);
+
+        let mut marker = format!("# {} \"{}\"", line, name);
+        if let Some(flag) = flag {
+            marker.push(' ');
+            marker.push_str(flag);
+        }
+        if system_header {
+            marker.push_str(" 3");
+        }
+        self.output.push_str(&marker);
+        self.output.push('\n');
+        self.output_sync = Some((self.output.len(), line_number));
+    }
+
+    /// Brings the output into line with `line_number` of the current file
+    /// before a line of output from it is written, with blank lines for the
+    /// lines that have been left out, or a linemarker when there are too many
+    /// of them or the output has already gone past the line.
+    fn sync_output(&mut self, line_number: usize) {
+        let (length, line) = match self.output_sync {
+            Some(sync) => sync,
+            None => return,
+        };
+        if !(self.output.is_empty() || self.output.ends_with('\n')) {
+            return;
+        }
+
+        let line = line + self.output[length..].matches('\n').count();
+        if line <= line_number && line_number - line <= MAX_SYNC_BLANK_LINES {
+            for _ in line..line_number {
+                self.output.push('\n');
+            }
+            self.output_sync = Some((self.output.len(), line_number));
+        }
+        else {
+            self.write_linemarker(line_number, None);
+        }
+    }
+
+    /// Warns about trigraphs and nested comments on a line.
+    fn check_line_warnings(&self, line: &str, line_number: usize, in_comment: &mut bool) {
+        let column = |offset: usize| line[..offset].chars().count() + 1;
+
+        for offset in find_nested_comments(line, in_comment) {
+            let diagnostic = Diagnostic::warning(Warning::Comment, String::from("\"/*\" within comment"));
+            self.emit(diagnostic.at(self.location(line_number, column(offset)), 2));
+        }
+
+        if self.current_state() == State::WithinTrueBlock {
+            for offset in find_trigraphs(line) {
+                let message = format!("trigraph {} ignored", &line[offset..offset + 3]);
+                self.emit(Diagnostic::warning(Warning::Trigraphs, message).at(self.location(line_number, column(offset)), 3));
+            }
+        }
     }
 
-    pub fn preprocess_source(&mut self, source: &str) {
+    /// Preprocesses the lines of `source`.  Errors are reported and recovered
+    /// from so that one run finds as many of them as it can, which means this
+    /// only fails once `-fmax-errors` is reached.  `error_count` tells whether
+    /// there were any.
+    pub fn preprocess_source(&mut self, source: &str) -> Result<(), PreprocessError> {
         let directive = Regex::new(r"^\s*#").unwrap();
         //let source = expand_line_wraps(&source);
         //let source = strip_comments(&source);
 
+        let mut in_comment = false;
+
         for (line_number, ref line) in lineiter::iter_lines(source) {
-            if directive.is_match(&line) {
-                self.run_directive(&line);
+            self.check_line_warnings(line, line_number, &mut in_comment);
+
+            let result = if directive.is_match(&line) {
+                self.run_directive(&line, line_number)
+            }
+            else if self.current_state() == State::WithinTrueBlock {
+                self.feed_line(&line, line_number)
             }
-            else if self.state_stack.last().cloned().unwrap_or(State::WithinTrueBlock) == State::WithinTrueBlock {
-                self.feed_line(&line, line_number);
+            else {
+                Ok(())
+            };
+
+            // every error has been reported by the time it gets here, so the
+            // line is skipped and preprocessing carries on with the next one,
+            // unless there have been too many errors already
+            self.recover(result, ())?;
+
+            if self.diagnostics.limit_reached() {
+                return Err(PreprocessError::TooManyErrors { max_errors: self.config.max_errors });
+            }
+        }
+
+        // conditionals left open are reported and then closed, so that they do
+        // not carry on into the file that included this one
+        while self.file_conditional_depth() > 0 {
+            let opened_line = self.state_stack.pop().unwrap().opened_line;
+            self.report_error(opened_line, "unterminated conditional directive, the #if is never closed by an #endif");
+        }
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::ops::Deref;
+    use std::path::{Path, PathBuf};
+
+    use regex::Regex;
+
+    use config::{Config, Language, Standard};
+    use diagnostic::DiagnosticFormat;
+    use error::PreprocessError;
+    use super::{closest_match, PreProcessor};
+
+    /// A directory written by `write_files`, removed again when dropped.
+    struct TestDir(PathBuf);
+
+    impl Deref for TestDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes `files` into a new directory for the test called `test`.
+    fn write_files(test: &str, files: &[(&str, &str)]) -> TestDir {
+        let dir = std::env::temp_dir().join(format!("rcpp-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        for &(name, contents) in files.iter() {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+        }
+        TestDir(dir)
+    }
+
+    fn test_config() -> Config {
+        let mut config = Config::new();
+        config.line_markers = false;
+        config
+    }
+
+    /// Preprocesses `source` as the file `main.c` in `dir`.
+    fn run<'a>(config: &'a Config, dir: &Path, source: &str) -> PreProcessor<'a> {
+        let mut cpp = PreProcessor::new(config);
+        let _ = cpp.preprocess_file(&dir.join("main.c"), source);
+        cpp
+    }
+
+    /// Preprocesses `source` as the file `main.c` in `dir`, returning the
+    /// output and the number of errors.
+    fn preprocess_in(config: &Config, dir: &Path, source: &str) -> (String, usize) {
+        let cpp = run(config, dir, source);
+        (cpp.get_output(), cpp.error_count())
+    }
+
+    /// The non-blank lines of the output, with their whitespace trimmed.
+    fn output_lines(output: &str) -> Vec<&str> {
+        output.lines().map(|line| line.trim()).filter(|line| !line.is_empty()).collect()
+    }
+
+    /// The kind, line and column of each diagnostic that was collected for
+    /// the JSON format, leaving out their notes.  Diagnostics without a
+    /// location are at line and column 0.
+    fn json_diagnostics(cpp: &PreProcessor) -> Vec<(String, usize, usize)> {
+        let json = cpp.diagnostics.finish(&cpp.sources).unwrap_or_default();
+        let diagnostic = Regex::new(concat!(r#"(?m)^\[?\{"kind": "(\w+)", .*?"locations": \["#,
+                                            r#"(?:\{"caret": \{"file": "(?:[^"\\]|\\.)*", "line": (\d+), "column": (\d+)\})?"#)).unwrap();
+
+        diagnostic.captures_iter(&json).map(|caps| {
+            let number = |i| caps.get(i).map_or(0, |m| m.as_str().parse().unwrap());
+            (caps[1].to_string(), number(2), number(3))
+        }).collect()
+    }
+
+    #[test]
+    fn include_depth_is_limited() {
+        let dir = write_files("include-depth", &[("self.h", "x\n#include \"self.h\"\n")]);
+        let mut config = test_config();
+        config.max_include_depth = 3;
+
+        let cpp = run(&config, &dir, "#include \"self.h\"\n");
+        assert_eq!(output_lines(&cpp.get_output()), vec!["x", "x", "x"]);
+        assert_eq!(cpp.error_count(), 1);
+
+        match cpp.errors() {
+            &[PreprocessError::IncludeDepth { max_depth: 3, ref include_chain, .. }] => {
+                assert_eq!(include_chain.matches("self.h").count(), 3);
+                assert!(include_chain.contains("main.c"));
             }
+            other => panic!("expected an include depth error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn include_depth_allows_the_maximum() {
+        let dir = write_files("include-depth-max", &[("a.h", "#include \"b.h\"\n"), ("b.h", "b\n")]);
+        let mut config = test_config();
+        config.max_include_depth = 2;
+
+        let (output, errors) = preprocess_in(&config, &dir, "#include \"a.h\"\n");
+        assert_eq!(output_lines(&output), vec!["b"]);
+        assert_eq!(errors, 0);
+    }
+
+    #[test]
+    fn computed_include_joins_angled_tokens() {
+        let dir = write_files("computed-include", &[("sys/a.h", "a\n"), ("My Headers/c.h", "c\n"), ("b.h", "b\n")]);
+        let mut config = test_config();
+        config.include_dirs.push(dir.to_path_buf());
+
+        let source = concat!("#define HEADER <sys/a.h>\n#include HEADER\n",
+                             "#define SPACED <My   Headers/c.h >\n#include SPACED\n",
+                             "#define QUOTED \"b.h\"\n#include QUOTED\n");
+        let (output, errors) = preprocess_in(&config, &dir, source);
+        assert_eq!(output_lines(&output), vec!["a", "c", "b"]);
+        assert_eq!(errors, 0);
+    }
+
+    #[test]
+    fn has_include_checks_without_including() {
+        let dir = write_files("has-include", &[("present.h", "#error included\n")]);
+        let mut config = test_config();
+        config.include_dirs.push(dir.to_path_buf());
+
+        let source = concat!(
+            "#define PRESENT <present.h>\n",
+            "#if __has_include(\"present.h\") && __has_include(<present.h>) && __has_include(PRESENT)\n",
+            "yes\n",
+            "#endif\n",
+            "#if __has_include(<missing.h>)\n",
+            "no\n",
+            "#endif\n",
+        );
+        let (output, errors) = preprocess_in(&config, &dir, source);
+        assert_eq!(output_lines(&output), vec!["yes"]);
+        assert_eq!(errors, 0);
+    }
+
+    #[test]
+    fn recoverable_errors_are_counted_not_returned() {
+        let config = test_config();
+        let mut cpp = PreProcessor::new(&config);
+        let source = "#error stop\n#include <missing.h>\n#if 1 +\n#endif\nafter\n";
+
+        assert!(cpp.preprocess_file(Path::new("main.c"), source).is_ok());
+        assert_eq!(output_lines(&cpp.get_output()), vec!["after"]);
+        assert_eq!(cpp.error_count(), 3);
+
+        match cpp.errors() {
+            &[PreprocessError::ErrorDirective { .. }, PreprocessError::HeaderNotFound { .. }, PreprocessError::DirectiveSyntax { .. }] => {}
+            other => panic!("expected the three errors, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unreadable_files_and_the_error_limit_are_returned() {
+        let mut config = test_config();
+        let mut cpp = PreProcessor::new(&config);
+        match cpp.preprocess_path(Path::new("/nonexistent/main.c")) {
+            Err(PreprocessError::Io { .. }) => {}
+            other => panic!("expected an I/O error, got {:?}", other),
+        }
+
+        config.max_errors = 2;
+        let mut cpp = PreProcessor::new(&config);
+        match cpp.preprocess_file(Path::new("main.c"), "#error 1\n#error 2\n#error 3\n") {
+            Err(PreprocessError::TooManyErrors { max_errors: 2 }) => {}
+            other => panic!("expected too many errors, got {:?}", other),
+        }
+        assert_eq!(cpp.error_count(), 2);
+    }
+
+    #[test]
+    fn embed_parameters() {
+        let dir = write_files("embed", &[("data.bin", "ABCD"), ("empty.bin", "")]);
+
+        let source = concat!(
+            "#embed \"data.bin\" limit(2) prefix(0x00,) suffix(,0xff)\n",
+            "#embed \"empty.bin\" prefix(no) if_empty(-1)\n",
+            "#embed \"data.bin\" limit(0) __if_empty__(none)\n",
+            "#embed \"data.bin\"\n",
+        );
+        let (output, errors) = preprocess_in(&test_config(), &dir, source);
+        assert_eq!(output_lines(&output), vec!["0x00,", "65,66", ",0xff", "-1", "none", "65,66,67,68"]);
+        assert_eq!(errors, 0);
+    }
+
+    #[test]
+    fn has_embed_values() {
+        let dir = write_files("has-embed", &[("data.bin", "ABCD"), ("empty.bin", "")]);
+
+        let source = concat!(
+            "#if __has_embed(\"data.bin\") == __STDC_EMBED_FOUND__\nfound\n#endif\n",
+            "#if __has_embed(\"empty.bin\") == __STDC_EMBED_EMPTY__\nempty\n#endif\n",
+            "#if __has_embed(\"data.bin\" limit(0)) == __STDC_EMBED_EMPTY__\nlimited\n#endif\n",
+            "#if __has_embed(\"missing.bin\") == __STDC_EMBED_NOT_FOUND__\nmissing\n#endif\n",
+        );
+        let (output, errors) = preprocess_in(&test_config(), &dir, source);
+        assert_eq!(output_lines(&output), vec!["found", "empty", "limited", "missing"]);
+        assert_eq!(errors, 0);
+    }
+
+    #[test]
+    fn null_directives_and_linemarkers() {
+        let source = "#\n# /* comment */\n  #  \n# 10 \"other.c\"\n#line 20\ntext\n";
+        let (output, errors) = preprocess_in(&test_config(), Path::new("."), source);
+        assert_eq!(output_lines(&output), vec!["text"]);
+        assert_eq!(errors, 0);
+
+        let (_, errors) = preprocess_in(&test_config(), Path::new("."), "# 1x\n#!\n");
+        assert_eq!(errors, 2);
+    }
+
+    #[test]
+    fn embed_waits_for_a_pending_macro_invocation() {
+        let dir = write_files("embed-order", &[("data.bin", "AB")]);
+
+        let source = "#define F(x) [x]\nF(1\n#embed \"data.bin\"\n)\n";
+        let (output, errors) = preprocess_in(&test_config(), &dir, source);
+        assert_eq!(output_lines(&output), vec!["[1]", "65,66"]);
+        assert_eq!(errors, 0);
+    }
+
+    #[test]
+    fn elifdef_and_elifndef_in_c23() {
+        let mut config = test_config();
+        config.std = Standard::C23;
+
+        let source = concat!(
+            "#define B\n",
+            "#ifdef A\na\n#elifdef B\nb\n#else\nelse\n#endif\n",
+            "#if 1\none\n#elifndef C\nnot c\n#endif\n",
+            "#if 0\n#elifndef C\nc\n#endif\n",
+        );
+        let (output, errors) = preprocess_in(&config, Path::new("."), source);
+        assert_eq!(output_lines(&output), vec!["b", "one", "c"]);
+        assert_eq!(errors, 0);
+    }
+
+    #[test]
+    fn elifdef_is_rejected_before_c23() {
+        let mut config = test_config();
+        config.std = Standard::C17;
+
+        let (_, errors) = preprocess_in(&config, Path::new("."), "#if 1\n#elifdef B\n#endif\n");
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn elifdef_is_ignored_in_skipped_groups_before_c23() {
+        let mut config = test_config();
+        config.std = Standard::C17;
+
+        let source = "#if 0\n#elifdef B\nskipped\n#else\nelse\n#endif\n#if 0\n#if 1\n#elifndef B\n#endif\n#endif\n";
+        let (output, errors) = preprocess_in(&config, Path::new("."), source);
+        assert_eq!(output_lines(&output), vec!["else"]);
+        assert_eq!(errors, 0);
+    }
+
+    #[test]
+    fn skipped_groups_ignore_other_directives() {
+        let source = "#if 0\n#error no\n#warning no\n#pragma once\n#bogus\n#include <missing.h>\n#endif\n";
+        let config = test_config();
+        let cpp = run(&config, Path::new("."), source);
+        assert_eq!(cpp.error_count(), 0);
+        assert_eq!(cpp.diagnostics.warning_count(), 0);
+    }
+
+    #[test]
+    fn conditional_structure_is_checked() {
+        let cases = [
+            ("#if 1\n#else\n#else\n#endif\n", 1),
+            ("#if 0\n#else\n#elif 1\n#endif\n", 1),
+            ("#endif\n", 1),
+            ("#if 1\n", 1),
+            ("#if 1\n#elif 0\n#else\n#endif\n", 0),
+        ];
+
+        for &(source, expected_errors) in cases.iter() {
+            let (_, errors) = preprocess_in(&test_config(), Path::new("."), source);
+            assert_eq!(errors, expected_errors, "in {:?}", source);
         }
     }
+
+    #[test]
+    fn conditionals_do_not_cross_files() {
+        let dir = write_files("conditional-files", &[("open.h", "#if 1\n"), ("close.h", "#endif\n")]);
+
+        let (_, errors) = preprocess_in(&test_config(), &dir, "#include \"open.h\"\n#include \"close.h\"\n");
+        assert_eq!(errors, 2);
+    }
+
+    #[test]
+    fn diagnostics_point_at_each_token() {
+        let dir = Path::new(".");
+        let mut config = test_config();
+        config.warnings.apply("undef").unwrap();
+        config.diagnostics_format = DiagnosticFormat::Json;
+
+        let cpp = run(&config, dir, concat!("#define F(a) a\n",
+                                             "#define G F(1, 2)\n",
+                                             "#if X + X\n",
+                                             "#endif\n",
+                                             "F(1) + F(1, 2)\n",
+                                             "x = G;\n",
+                                             "#if defined(A) || Y\n",
+                                             "#endif\n"));
+        assert_eq!(json_diagnostics(&cpp), vec![
+            ("warning".to_string(), 3, 5),
+            ("warning".to_string(), 3, 9),
+            ("error".to_string(), 5, 8),
+            // the invocation of F came out of G, so the error is at G
+            ("error".to_string(), 6, 5),
+            ("warning".to_string(), 7, 19),
+        ]);
+    }
+
+    #[test]
+    fn diagnostics_in_headers_carry_the_include_stack() {
+        let dir = write_files("included-from", &[("a.h", "\n#include \"b.h\"\n"), ("b.h", "#error oops\n")]);
+        let mut config = test_config();
+        config.diagnostics_format = DiagnosticFormat::Json;
+
+        let cpp = run(&config, &dir, "#include \"a.h\"\n");
+        let json = cpp.diagnostics.finish(&cpp.sources).unwrap();
+        let expected = format!(r#""included-from": [{{"file": "{}", "line": 2, "column": 2}}, {{"file": "{}", "line": 1, "column": 2}}]"#,
+                               dir.join("a.h").display(), dir.join("main.c").display());
+        assert!(json.contains(&expected), "{}", json);
+    }
+
+    #[test]
+    fn expansion_errors_have_expansion_notes() {
+        let dir = Path::new(".");
+        let mut config = test_config();
+        config.diagnostics_format = DiagnosticFormat::Json;
+
+        let cpp = run(&config, dir, concat!("#define INNER(a) a\n",
+                                             "#define OUTER INNER(1, 2)\n",
+                                             "#define TOP OUTER\n",
+                                             "TOP;\n"));
+        let json = cpp.diagnostics.finish(&cpp.sources).unwrap();
+        let note = Regex::new(r#""kind": "note", "message": "([^"]*)", "locations": \[\{"caret": \{"file": "[^"]*", "line": (\d+)"#).unwrap();
+        let notes: Vec<(String, usize)> = note.captures_iter(&json).map(|caps| (caps[1].to_string(), caps[2].parse().unwrap())).collect();
+
+        assert_eq!(json_diagnostics(&cpp), vec![("error".to_string(), 4, 1)]);
+        assert_eq!(notes, vec![
+            ("expanded from macro 'OUTER'".to_string(), 2),
+            ("expanded from macro 'TOP'".to_string(), 3),
+            ("macro 'INNER' defined here".to_string(), 1),
+        ]);
+    }
+
+    #[test]
+    fn diagnostic_pragmas_follow_push_and_pop() {
+        let dir = Path::new(".");
+        let mut config = test_config();
+        config.warnings.apply("undef").unwrap();
+        config.diagnostics_format = DiagnosticFormat::Json;
+
+        let cpp = run(&config, dir, concat!("#pragma GCC diagnostic push\n",
+                                             "#pragma GCC diagnostic ignored \"-Wundef\"\n",
+                                             "#if A\n",
+                                             "#endif\n",
+                                             "#pragma GCC diagnostic error \"-Wundef\"\n",
+                                             "#if B\n",
+                                             "#endif\n",
+                                             "#pragma GCC diagnostic pop\n",
+                                             "#if C\n",
+                                             "#endif\n"));
+        assert_eq!(json_diagnostics(&cpp), vec![("error".to_string(), 6, 5), ("warning".to_string(), 9, 5)]);
+    }
+
+    #[test]
+    fn diagnostic_pragmas_leave_other_warnings_to_the_compiler() {
+        let config = test_config();
+        let cpp = run(&config, Path::new("."), concat!("#pragma GCC diagnostic ignored \"-Wunused-parameter\"\n",
+                                                     "#pragma clang diagnostic warning \"-Wdeprecated-declarations\"\n"));
+        assert_eq!(cpp.diagnostics.warning_count(), 0);
+
+        let cpp = run(&config, Path::new("."), "#pragma GCC diagnostic ignored -Wundef\n#pragma GCC diagnostic ignored \"undef\"\n");
+        assert_eq!(cpp.diagnostics.warning_count(), 2);
+    }
+
+    #[test]
+    fn failing_pragma_operator_keeps_the_rest_of_the_line() {
+        let dir = Path::new(".");
+
+        let (output, errors) = preprocess_in(&test_config(), dir, "a _Pragma(\"GCC dependency \\\"missing.h\\\"\") b\nc\n");
+        assert_eq!(output_lines(&output), vec!["a", "b", "c"]);
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn closest_match_suggests_near_misses() {
+        let candidates = || vec!["include".to_string(), "ifdef".to_string(), "Stdio.h".to_string()];
+
+        assert_eq!(closest_match("incldue", candidates()), Some("include".to_string()));
+        assert_eq!(closest_match("stdio.h", candidates()), Some("Stdio.h".to_string()));
+        assert_eq!(closest_match("pragma", candidates()), None);
+    }
+
+    #[test]
+    fn missing_headers_and_unknown_directives_get_suggestions() {
+        let dir = write_files("suggestions", &[("hello.h", "")]);
+        let mut config = test_config();
+        config.diagnostics_format = DiagnosticFormat::Json;
+
+        let cpp = run(&config, &dir, "#include \"helo.h\"\n#incldue \"hello.h\"\n");
+        let json = cpp.diagnostics.finish(&cpp.sources).unwrap();
+        let note = Regex::new(r#""kind": "note", "message": "((?:[^"\\]|\\.)*)""#).unwrap();
+        let notes: Vec<String> = note.captures_iter(&json).map(|caps| caps[1].to_string()).collect();
+
+        assert_eq!(notes, vec![
+            r#"did you mean \"hello.h\"?"#.to_string(),
+            format!("searched in {}", dir.display()),
+            "did you mean #include?".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn pragmas_wait_for_a_pending_macro_invocation() {
+        let dir = Path::new(".");
+
+        let source = "#define F(x) [x]\nF(1\n#pragma omp parallel\n)\n";
+        let (output, errors) = preprocess_in(&test_config(), dir, source);
+        assert_eq!(output_lines(&output), vec!["[1]", "#pragma omp parallel"]);
+        assert_eq!(errors, 0);
+    }
+
+    #[test]
+    fn pragma_operator_runs_or_passes_through_pragmas() {
+        let dir = Path::new(".");
+        let mut config = test_config();
+        config.warnings.apply("undef").unwrap();
+
+        let source = concat!("a _Pragma(\"omp parallel\") b\n",
+                             "#define NO_UNDEF _Pragma(\"GCC diagnostic ignored \\\"-Wundef\\\"\")\n",
+                             "NO_UNDEF\n",
+                             "#if X\n",
+                             "#endif\n");
+        let cpp = run(&config, dir, source);
+        // the compiler needs to see diagnostic pragmas as well
+        assert_eq!(output_lines(&cpp.get_output()), vec!["a", "#pragma omp parallel", "b", "#pragma GCC diagnostic ignored \"-Wundef\""]);
+        assert_eq!(cpp.diagnostics.warning_count(), 0);
+        assert_eq!(cpp.error_count(), 0);
+    }
+
+    #[test]
+    fn push_macro_and_pop_macro_restore_definitions() {
+        let dir = Path::new(".");
+
+        let source = concat!("#define min(a, b) a\n",
+                             "#pragma push_macro(\"min\")\n",
+                             "#undef min\n",
+                             "min(1, 2)\n",
+                             "#define min 3\n",
+                             "min\n",
+                             "#pragma pop_macro(\"min\")\n",
+                             "min(1, 2)\n",
+                             "#pragma push_macro(\"X\")\n",
+                             "#define X 1\n",
+                             "#pragma pop_macro(\"X\")\n",
+                             "X\n");
+        let (output, errors) = preprocess_in(&test_config(), dir, source);
+        let text: Vec<&str> = output_lines(&output).into_iter().filter(|line| !line.starts_with("#pragma")).collect();
+        assert_eq!(text, vec!["min(1, 2)", "3", "1", "X"]);
+        assert_eq!(errors, 0);
+    }
+
+    #[test]
+    fn poisoned_identifiers_are_errors_except_in_older_macros() {
+        let dir = Path::new(".");
+        let mut config = test_config();
+        config.diagnostics_format = DiagnosticFormat::Json;
+
+        let cpp = run(&config, dir, concat!("#define OLD gets(buf)\n",
+                                             "#pragma GCC poison gets\n",
+                                             "OLD;\n",
+                                             "gets(buf);\n",
+                                             "#define NEW(x) x\n",
+                                             "NEW(gets);\n",
+                                             "#ifdef gets\n",
+                                             "#endif\n",
+                                             "#if defined(gets)\n",
+                                             "#endif\n",
+                                             "#define BAD gets\n",
+                                             "#define sprintf my_sprintf\n",
+                                             "#pragma GCC poison sprintf\n",
+                                             "sprintf;\n"));
+        let output = cpp.get_output();
+        let text: Vec<&str> = output_lines(&output).into_iter().filter(|line| !line.starts_with("#pragma")).collect();
+        assert_eq!(text, vec!["gets(buf);", "gets(buf);", "gets;", "sprintf;"]);
+        assert_eq!(json_diagnostics(&cpp), vec![
+            ("error".to_string(), 4, 1),
+            ("error".to_string(), 6, 1),
+            ("error".to_string(), 7, 8),
+            ("error".to_string(), 9, 13),
+            ("error".to_string(), 11, 13),
+            ("warning".to_string(), 13, 2),
+            ("error".to_string(), 14, 1),
+        ]);
+    }
+
+    #[test]
+    fn linemarkers_keep_the_output_in_line_with_the_source() {
+        let dir = write_files("linemarkers", &[("sys.h", "#pragma GCC system_header\n#if X\n#endif\ns\n")]);
+        let mut config = Config::new();
+        config.warnings.apply("undef").unwrap();
+
+        let source = concat!("#define A 1\n",
+                             "A\n",
+                             "#if 0\n",
+                             "x\n",
+                             "#endif\n",
+                             "b\n",
+                             "#if 0\n",
+                             "x\nx\nx\nx\nx\nx\nx\nx\n",
+                             "#endif\n",
+                             "c\n",
+                             "#include \"sys.h\"\n",
+                             "d\n");
+        let cpp = run(&config, &dir, source);
+        let main = dir.join("main.c").display().to_string();
+        let sys = dir.join("sys.h").display().to_string();
+        assert_eq!(cpp.get_output(), format!(concat!("# 1 \"{main}\"\n",
+                                                     "\n",
+                                                     "1\n",
+                                                     "\n\n\n",
+                                                     "b\n",
+                                                     "# 17 \"{main}\"\n",
+                                                     "c\n",
+                                                     "# 1 \"{sys}\" 1\n",
+                                                     "# 2 \"{sys}\" 3\n",
+                                                     "\n\n",
+                                                     "s\n",
+                                                     "# 19 \"{main}\" 2\n",
+                                                     "d\n"), main = main, sys = sys));
+        // the -Wundef warning is in a system header
+        assert_eq!(cpp.diagnostics.warning_count(), 0);
+    }
+
+    #[test]
+    fn linemarkers_follow_multi_line_invocations_and_line_directives() {
+        let dir = Path::new(".");
+        let config = Config::new();
+
+        let cpp = run(&config, dir, "#define F(a, b) [a b]\nF(1,\n2)\nz\n#line 50\nw\n");
+        let main = dir.join("main.c").display().to_string();
+        assert_eq!(cpp.get_output(), format!("# 1 \"{main}\"\n\n[1 2]\n\nz\n# 50 \"{main}\"\nw\n", main = main));
+    }
+
+    #[test]
+    fn message_pragmas_are_reported_and_passed_through() {
+        let dir = Path::new(".");
+        let mut config = test_config();
+        config.diagnostics_format = DiagnosticFormat::Json;
+
+        let cpp = run(&config, dir, concat!("#pragma message(\"building\")\n",
+                                             "#pragma GCC warning \"old\"\n",
+                                             "#pragma GCC error \"bad\"\n"));
+        assert_eq!(output_lines(&cpp.get_output()), vec!["#pragma message(\"building\")"]);
+        assert_eq!(json_diagnostics(&cpp), vec![
+            ("note".to_string(), 1, 2),
+            ("warning".to_string(), 2, 2),
+            ("error".to_string(), 3, 2),
+        ]);
+    }
+
+    #[test]
+    fn ident_everywhere_and_assertions_in_gnu_mode() {
+        let dir = Path::new(".");
+
+        let source = concat!("#ident \"v1\"\n",
+                             "#sccs \"v2\"\n",
+                             "#assert machine(x86)\n",
+                             "#if #machine(x86) && #machine && !#machine(arm)\n",
+                             "yes\n",
+                             "#endif\n",
+                             "#unassert machine\n",
+                             "#if #machine\n",
+                             "no\n",
+                             "#endif\n");
+        let (output, errors) = preprocess_in(&test_config(), dir, source);
+        assert_eq!(output_lines(&output), vec!["#ident \"v1\"", "#ident \"v2\"", "yes"]);
+        assert_eq!(errors, 0);
+
+        let mut config = test_config();
+        config.gnu_extensions = false;
+        let (output, errors) = preprocess_in(&config, dir, "#ident \"v1\"\n#assert machine(x86)\n");
+        assert_eq!(output_lines(&output), vec!["#ident \"v1\""]);
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn import_skips_headers_that_were_already_entered() {
+        let dir = write_files("import", &[("a.h", "a\n"), ("b.h", "b\n")]);
+        let mut config = test_config();
+        config.language = Language::ObjectiveC;
+
+        let source = "#include \"a.h\"\n#import \"a.h\"\n#import \"b.h\"\n#import \"b.h\"\n#include \"b.h\"\n";
+        let (output, errors) = preprocess_in(&config, &dir, source);
+        assert_eq!(output_lines(&output), vec!["a", "b"]);
+        assert_eq!(errors, 0);
+
+        let (output, errors) = preprocess_in(&test_config(), &dir, "#import \"a.h\"\n");
+        assert_eq!(output_lines(&output), Vec::<&str>::new());
+        assert_eq!(errors, 1);
+    }
 }
@@ -0,0 +1,106 @@
+
+use std::path::{Path, PathBuf};
+
+
+/// Identifies a file that has been added to a `SourceManager`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct FileId(usize);
+
+/// A position in a source file.  Lines are the physical lines that
+/// `lineiter::iter_lines` numbers, and columns count characters, both
+/// starting at 1.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: FileId,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A `#line` directive or linemarker, which changes the presumed line number
+/// and file name of the lines that come after it.
+struct LineDirective {
+    line: usize,
+    presumed_line: usize,
+    presumed_name: Option<String>,
+}
+
+struct SourceFile {
+    path: PathBuf,
+    lines: Vec<String>,
+    line_directives: Vec<LineDirective>,
+}
+
+
+/// Keeps the text of every file the preprocessor reads, so that diagnostics
+/// can show the lines they are about.
+pub struct SourceManager {
+    files: Vec<SourceFile>,
+}
+
+impl SourceManager {
+    pub fn new() -> SourceManager {
+        SourceManager { files: Vec::new() }
+    }
+
+    pub fn add_file(&mut self, path: &Path, source: &str) -> FileId {
+        self.files.push(SourceFile {
+            path: path.to_path_buf(),
+            lines: source.lines().map(String::from).collect(),
+            line_directives: Vec::new(),
+        });
+
+        FileId(self.files.len() - 1)
+    }
+
+    pub fn path(&self, file: FileId) -> &Path {
+        &self.files[file.0].path
+    }
+
+    /// The text of a physical line, without its line break.
+    pub fn line_text(&self, file: FileId, line: usize) -> Option<&str> {
+        if line == 0 {
+            return None;
+        }
+        self.files[file.0].lines.get(line - 1).map(|text| text.as_str())
+    }
+
+    /// Records a `#line` directive on `line`, which says that the next line is
+    /// `presumed_line`, optionally in a file called `presumed_name`.
+    pub fn add_line_directive(&mut self, file: FileId, line: usize, presumed_line: usize, presumed_name: Option<String>) {
+        self.files[file.0].line_directives.push(LineDirective {
+            line: line,
+            presumed_line: presumed_line,
+            presumed_name: presumed_name,
+        });
+    }
+
+    /// The file name and line number that a location has once `#line`
+    /// directives are taken into account.
+    pub fn presumed(&self, location: SourceLocation) -> (String, usize) {
+        let file = &self.files[location.file.0];
+        let mut name = file.path.display().to_string();
+        let mut line = location.line;
+
+        for directive in file.line_directives.iter().take_while(|directive| directive.line < location.line) {
+            line = directive.presumed_line + (location.line - directive.line - 1);
+            if let Some(ref presumed_name) = directive.presumed_name {
+                name = presumed_name.clone();
+            }
+        }
+
+        (name, line)
+    }
+
+    /// Describes a location as `file:line:column`, using its presumed file
+    /// name and line number.
+    pub fn describe(&self, location: SourceLocation) -> String {
+        let (name, line) = self.presumed(location);
+        format!("{}:{}:{}", name, line, location.column)
+    }
+}
+
+impl Default for SourceManager {
+    fn default() -> SourceManager {
+        SourceManager::new()
+    }
+}
@@ -0,0 +1,244 @@
+
+//! The named warnings that can be turned on, off or into errors with the
+//! `-W` family of options.
+
+use std::collections::{HashMap, HashSet};
+
+use diagnostic::Severity;
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Warning {
+    /// `#warning` directives.
+    Cpp,
+    /// Identifiers in `#if` expressions that are not macros.
+    Undef,
+    /// Macros defined in the main file that are never used.
+    UnusedMacros,
+    /// Macros that are defined again with a different replacement.
+    MacroRedefined,
+    /// `defined` appearing as the result of macro expansion in an `#if`.
+    ExpansionToDefined,
+    /// Trigraphs, which are not replaced.
+    Trigraphs,
+    /// `/*` inside a block comment.
+    Comment,
+    /// `#include_next` in the primary source file.
+    IncludeNext,
+    /// Pragmas that are malformed.
+    Pragmas,
+    /// Expansions of macros marked by `#pragma clang deprecated`.
+    DeprecatedPragma,
+    /// Expansions in headers of macros marked by `#pragma clang restrict_expansion`.
+    RestrictExpansion,
+}
+
+const WARNINGS: &[Warning] = &[
+    Warning::Cpp,
+    Warning::Undef,
+    Warning::UnusedMacros,
+    Warning::MacroRedefined,
+    Warning::ExpansionToDefined,
+    Warning::Trigraphs,
+    Warning::Comment,
+    Warning::IncludeNext,
+    Warning::Pragmas,
+    Warning::DeprecatedPragma,
+    Warning::RestrictExpansion,
+];
+
+impl Warning {
+    /// The name of the warning as it is spelled after `-W`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Warning::Cpp => "cpp",
+            Warning::Undef => "undef",
+            Warning::UnusedMacros => "unused-macros",
+            Warning::MacroRedefined => "macro-redefined",
+            Warning::ExpansionToDefined => "expansion-to-defined",
+            Warning::Trigraphs => "trigraphs",
+            Warning::Comment => "comment",
+            Warning::IncludeNext => "include-next",
+            Warning::Pragmas => "pragmas",
+            Warning::DeprecatedPragma => "deprecated-pragma",
+            Warning::RestrictExpansion => "restrict-expansion",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Warning> {
+        WARNINGS.iter().cloned().find(|warning| warning.name() == name)
+    }
+
+    fn enabled_by_default(self) -> bool {
+        match self {
+            Warning::Cpp | Warning::MacroRedefined | Warning::IncludeNext | Warning::Trigraphs | Warning::Pragmas => true,
+            Warning::DeprecatedPragma | Warning::RestrictExpansion => true,
+            _ => false,
+        }
+    }
+
+    /// The option group, `all` or `extra`, that also enables the warning.
+    fn group(self) -> Option<&'static str> {
+        match self {
+            Warning::Comment | Warning::Trigraphs => Some("all"),
+            Warning::ExpansionToDefined => Some("extra"),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Level {
+    Ignored,
+    Warning,
+    Error,
+}
+
+
+/// Which warnings are reported, and which of them are errors.
+#[derive(Debug, Clone)]
+pub struct WarningOptions {
+    levels: HashMap<Warning, Level>,
+    /// `-Werror`, which makes every warning an error.
+    pub all_errors: bool,
+    /// The warnings that `-Wno-error=NAME` keeps as warnings under `-Werror`.
+    not_errors: HashSet<Warning>,
+    /// `-w`, which turns every warning off.
+    pub inhibit: bool,
+}
+
+impl WarningOptions {
+    pub fn new() -> WarningOptions {
+        let levels = WARNINGS.iter()
+            .map(|&warning| (warning, if warning.enabled_by_default() { Level::Warning } else { Level::Ignored }))
+            .collect();
+
+        WarningOptions {
+            levels: levels,
+            all_errors: false,
+            not_errors: HashSet::new(),
+            inhibit: false,
+        }
+    }
+
+    fn lookup(name: &str) -> Result<Warning, String> {
+        Warning::from_name(name).ok_or_else(|| format!("Unrecognized warning option -W{}", name))
+    }
+
+    /// Applies the text of a `-W` option, such as `undef`, `no-undef`, `all`,
+    /// `error` or `error=undef`.
+    pub fn apply(&mut self, option: &str) -> Result<(), String> {
+        match option {
+            "all" | "extra" => {
+                for &warning in WARNINGS.iter().filter(|warning| warning.group() == Some(option)) {
+                    self.enable(warning);
+                }
+            }
+            "error" => self.all_errors = true,
+            "no-error" => self.all_errors = false,
+            _ if option.starts_with("error=") => {
+                let warning = WarningOptions::lookup(&option["error=".len()..])?;
+                self.not_errors.remove(&warning);
+                self.set_level(warning, Level::Error);
+            }
+            _ if option.starts_with("no-error=") => {
+                let warning = WarningOptions::lookup(&option["no-error=".len()..])?;
+                self.not_errors.insert(warning);
+                if self.level(warning) == Level::Error {
+                    self.set_level(warning, Level::Warning);
+                }
+            }
+            _ if option.starts_with("no-") => {
+                let warning = WarningOptions::lookup(&option["no-".len()..])?;
+                self.set_level(warning, Level::Ignored);
+            }
+            _ => {
+                let warning = WarningOptions::lookup(option)?;
+                self.enable(warning);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Turns a warning on, leaving it alone if it is already an error.
+    fn enable(&mut self, warning: Warning) {
+        if self.level(warning) == Level::Ignored {
+            self.set_level(warning, Level::Warning);
+        }
+    }
+
+    pub fn level(&self, warning: Warning) -> Level {
+        self.levels.get(&warning).cloned().unwrap_or(Level::Ignored)
+    }
+
+    pub fn set_level(&mut self, warning: Warning, level: Level) {
+        self.levels.insert(warning, level);
+    }
+
+    /// Changes the level of a warning for `#pragma GCC diagnostic`, where
+    /// making something a warning also keeps `-Werror` from applying to it.
+    pub fn override_level(&mut self, warning: Warning, level: Level) {
+        if level == Level::Warning {
+            self.not_errors.insert(warning);
+        }
+        self.set_level(warning, level);
+    }
+
+    /// The severity that a warning is reported with, or `None` when it is not
+    /// reported at all.
+    pub fn severity(&self, warning: Warning) -> Option<Severity> {
+        match self.level(warning) {
+            _ if self.inhibit => None,
+            Level::Ignored => None,
+            Level::Error => Some(Severity::Error),
+            Level::Warning if self.all_errors && !self.not_errors.contains(&warning) => Some(Severity::Error),
+            Level::Warning => Some(Severity::Warning),
+        }
+    }
+}
+
+impl Default for WarningOptions {
+    fn default() -> WarningOptions {
+        WarningOptions::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use diagnostic::Severity;
+    use super::{Warning, WarningOptions};
+
+    fn options(args: &[&str]) -> WarningOptions {
+        let mut options = WarningOptions::new();
+        for arg in args.iter() {
+            options.apply(arg).unwrap();
+        }
+        options
+    }
+
+    #[test]
+    fn warnings_are_enabled_and_disabled_by_name() {
+        assert_eq!(options(&[]).severity(Warning::Undef), None);
+        assert_eq!(options(&[]).severity(Warning::MacroRedefined), Some(Severity::Warning));
+        assert_eq!(options(&["undef"]).severity(Warning::Undef), Some(Severity::Warning));
+        assert_eq!(options(&["undef", "no-undef"]).severity(Warning::Undef), None);
+        assert_eq!(options(&["all"]).severity(Warning::Comment), Some(Severity::Warning));
+        assert_eq!(options(&["all"]).severity(Warning::ExpansionToDefined), None);
+        assert!(options(&[]).apply("no-such-warning").is_err());
+    }
+
+    #[test]
+    fn warnings_are_made_errors() {
+        assert_eq!(options(&["error"]).severity(Warning::MacroRedefined), Some(Severity::Error));
+        assert_eq!(options(&["error", "no-error=macro-redefined"]).severity(Warning::MacroRedefined), Some(Severity::Warning));
+        assert_eq!(options(&["error=undef"]).severity(Warning::Undef), Some(Severity::Error));
+        assert_eq!(options(&["error=undef", "no-error=undef"]).severity(Warning::Undef), Some(Severity::Warning));
+
+        let mut inhibited = options(&["error"]);
+        inhibited.inhibit = true;
+        assert_eq!(inhibited.severity(Warning::MacroRedefined), None);
+    }
+}